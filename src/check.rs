@@ -1,4 +1,7 @@
 mod common;
+mod endpoints;
+mod filters;
+mod triggers;
 use anyhow::{
 	anyhow,
 	Result,
@@ -16,6 +19,8 @@ use gitlab::api::{
 	},
 	projects::{
 		merge_requests::{
+			approvals::MergeRequestApprovals,
+			MergeRequestCommits,
 			MergeRequestOrderBy,
 			MergeRequestState,
 			MergeRequests,
@@ -102,6 +107,29 @@ struct CheckState {
 	/// Once resurrected, they either build or they don't - no retry.
 	#[serde(default)]
 	resurrected_shas: HashSet<String>,
+
+	/// Start of the previous check: rebuild commands posted since then are new.
+	#[serde(default)]
+	last_check: Option<String>,
+
+	/// Rebuild command notes which already triggered a rebuild and are recent enough to be seen again, with the date
+	/// they were created.
+	#[serde(default)]
+	consumed_notes: std::collections::HashMap<u64, String>,
+
+	/// Per MR, the event which added the rebuild label and already triggered a rebuild.
+	/// Kept until the MR is seen without the label, even by checks which do not fetch the MR.
+	#[serde(default)]
+	rebuild_label_events: std::collections::HashMap<String, u64>,
+
+	/// Closures (`iid:state:date`) returned in `closed_events` mode, while they are within `max_age_days`.
+	#[serde(default)]
+	emitted_closures: HashSet<String>,
+
+	/// New heads held back by `quiet_period_seconds`, with the time they were first held back.
+	/// Heads which settled or were superseded by another push are dropped.
+	#[serde(default)]
+	pending_shas: std::collections::HashMap<String, String>,
 }
 
 impl CheckState {
@@ -231,6 +259,135 @@ impl CheckState {
 	}
 }
 
+/// The rebuild notes to remember as consumed, with their creation date: those consumed by previous checks and by this
+/// one, while the next check can still see them (created after `next_since`).
+///
+/// Notes consumed before are not found again, yet must be remembered as long as they are in the window.
+fn remember_notes(
+	previous: &std::collections::HashMap<u64, String>,
+	consumed: std::collections::HashMap<u64, String>,
+	next_since: DateTime<Utc>,
+) -> std::collections::HashMap<u64, String> {
+	previous
+		.iter()
+		.map(|(&id, created_at)| (id, created_at.clone()))
+		.chain(consumed)
+		.filter(|(_, created_at)| {
+			DateTime::<Utc>::from_str(created_at).is_ok_and(|created_at| created_at >= next_since)
+		})
+		.collect()
+}
+
+/// Record the event which added the rebuild label to an MR (`None` if the MR does not have the label), and whether
+/// it requests a rebuild: the label was added since the last rebuild it triggered.
+///
+/// MRs which are not seen keep their event, so that a label left in place never triggers twice.
+fn consume_label_event(
+	events: &mut std::collections::HashMap<String, u64>,
+	mr_key: String,
+	event: Option<u64>,
+) -> bool {
+	match event {
+		Some(event) => events.insert(mr_key, event) != Some(event),
+		None => {
+			events.remove(&mr_key);
+			false
+		},
+	}
+}
+
+/// When a version stops being held back by the quiet period: once its commit is `quiet_period` old.
+///
+/// The update date of the MR is not used, comments and label changes would hold it back as well.
+fn settles_at(committed_date: &str, quiet_period: chrono::Duration) -> Result<DateTime<Utc>> {
+	Ok(DateTime::<Utc>::from_str(committed_date)? + quiet_period)
+}
+
+/// The `last_check` to record, which starts the window of rebuild notes of the next check: this check, unless it held
+/// back versions, whose MRs were not looked for notes and must keep theirs in the window.
+fn next_last_check(last_check: Option<&str>, check_start: DateTime<Utc>, held_back: bool) -> String {
+	match last_check {
+		Some(last_check) if held_back => last_check.to_owned(),
+		_ => check_start.to_rfc3339(),
+	}
+}
+
+/// The commits of an MR (newest first, as listed by GitLab) pushed before `head` and after the last `known` one,
+/// oldest first.
+fn commits_since_known<'a>(commits: &'a [Commit], head: &str, known: impl Fn(&str) -> bool) -> Vec<&'a Commit> {
+	let mut since: Vec<&Commit> = commits
+		.iter()
+		.skip_while(|commit| commit.id != head)
+		.skip(1)
+		.take_while(|commit| !known(&commit.id))
+		.collect();
+	since.reverse();
+	since
+}
+
+/// Select the closures which were not returned yet (oldest first), and the closures to remember as returned.
+///
+/// Without a previous version nor any recorded closure (first check, lost state), only the latest closure is returned,
+/// so that a new resource does not tear down again everything closed within `max_age_days`.
+///
+/// Returned closures are remembered until they were closed before `cutoff`, even when the query no longer fetches them:
+/// a closed MR updated later (e.g. commented) is fetched again.
+fn select_closures(
+	mut closures: Vec<Version>,
+	first_version: bool,
+	emitted: &HashSet<String>,
+	cutoff: DateTime<Utc>,
+) -> (Vec<Version>, HashSet<String>) {
+	closures.sort_by(|a, b| a.committed_date.cmp(&b.committed_date));
+	let key = |version: &Version| {
+		format!("{}:{}:{}", version.mr_key(), version.kind.as_deref().unwrap_or_default(), version.committed_date)
+	};
+
+	let mut new_closures: Vec<Version> =
+		closures.iter().filter(|version| !emitted.contains(&key(version))).cloned().collect();
+	if first_version && emitted.is_empty() && new_closures.len() > 1 {
+		eprintln!("First check: only returning the latest of {} closures", new_closures.len());
+		new_closures.drain(..new_closures.len() - 1);
+	}
+	/* keys are `mr:kind:date`, with colons in the date only */
+	let is_recent = |key: &String| {
+		key.splitn(3, ':')
+			.nth(2)
+			.and_then(|date| DateTime::<Utc>::from_str(date).ok())
+			.is_none_or(|closed_at| closed_at >= cutoff)
+	};
+	let remembered = emitted.iter().cloned().chain(closures.iter().map(key)).filter(is_recent).collect();
+	(new_closures, remembered)
+}
+
+fn emit_closures(
+	closures: Vec<Version>,
+	first_version: bool,
+	cutoff: DateTime<Utc>,
+	mut state: CheckState,
+) -> Result<()> {
+	let (new_closures, emitted_closures) = select_closures(closures, first_version, &state.emitted_closures, cutoff);
+
+	eprintln!("\n=== CLOSED EVENTS ===");
+	for version in &new_closures {
+		eprintln!(
+			"  ✅ NEW closure: MR #{} {} at {}",
+			version.iid,
+			version.kind.as_deref().unwrap_or_default(),
+			version.committed_date
+		);
+	}
+	if emitted_closures != state.emitted_closures {
+		state.emitted_closures = emitted_closures;
+		if let Err(e) = state.save() {
+			eprintln!("⚠️  Warning: Failed to save state: {}", e);
+		}
+	}
+
+	println!("{}", serde_json::to_string_pretty(&new_closures)?);
+	Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ResourceInput {
 	pub version: Option<Version>,
@@ -306,36 +463,69 @@ fn main() -> Result<()> {
 	};
 
 	let project_path = uri.path().trim_start_matches('/').trim_end_matches(".git");
-	eprintln!("Project path: {}", project_path);
+	let multi_project = input.source.is_multi_project();
+	if let Some(group) = &input.source.group {
+		eprintln!("Group: {}", group);
+	} else if let Some(projects) = &input.source.projects {
+		eprintln!("Projects: {:?}", projects);
+	} else {
+		eprintln!("Project path: {}", project_path);
+	}
 
-	// Build the query for opened merge requests only
+	// Build the query for opened (and/or merged) merge requests only - closed ones only for their closure
+	let closed_events = input.source.closed_events.unwrap_or(false);
+	let states = match input.source.state.unwrap_or_default() {
+		_ if closed_events => vec![MergeRequestState::Closed, MergeRequestState::Merged],
+		WatchedState::Opened => vec![MergeRequestState::Opened],
+		WatchedState::Merged => vec![MergeRequestState::Merged],
+		WatchedState::Both => vec![MergeRequestState::Opened, MergeRequestState::Merged],
+	};
 	let mut builder = MergeRequests::builder();
 	builder
 		.project(project_path)
-		.state(MergeRequestState::Opened) // ONLY fetch opened MRs - this fixes the core issue!
 		.order_by(MergeRequestOrderBy::UpdatedAt)
 		.sort(SortOrder::Descending) // Most recent first for efficiency
 		.updated_after(updated_after);
 
 	eprintln!("GitLab API query filters:");
-	eprintln!("  - State: Opened");
+	eprintln!("  - State: {:?}", states);
 	eprintln!("  - Order by: UpdatedAt (Descending)");
 	eprintln!("  - Updated after: {}", updated_after);
 
 	// Apply optional filters
-	if let Some(target_branch) = &input.source.target_branch {
+	let mut filters = filters::Filters::new(&input.source)?;
+	if let Some(target_branch) = input.source.target_branch.as_deref().or(filters.target_branch()) {
 		eprintln!("  - Target branch: {}", target_branch);
 		builder.target_branch(target_branch);
 	} else {
 		eprintln!("  - Target branch: Not specified (all branches)");
 	}
+	if let Some(target_branches) = &input.source.target_branches {
+		eprintln!("  - Target branches: {:?}", target_branches);
+	}
+	if let Some(source_branch) = filters.source_branch() {
+		builder.source_branch(source_branch);
+	}
+	if let Some(source_branches) = &input.source.source_branches {
+		eprintln!("  - Source branches: {:?}", source_branches);
+	}
 
 	if let Some(labels) = &input.source.labels {
+		// Wildcards such as `team::*` are matched below, GitLab only knows literal labels
+		let literals: Vec<&String> = labels.iter().filter(|label| !filters::is_label_pattern(label)).collect();
 		eprintln!("  - Labels filter: {:?}", labels);
-		builder.labels(labels.iter());
+		if !literals.is_empty() {
+			builder.labels(literals);
+		}
 	} else {
 		eprintln!("  - Labels filter: Not specified (all labels)");
 	}
+	if let Some(any_labels) = &input.source.any_labels {
+		eprintln!("  - Any of labels: {:?}", any_labels);
+	}
+	if let Some(ignore_labels) = &input.source.ignore_labels {
+		eprintln!("  - Ignored labels: {:?}", ignore_labels);
+	}
 
 	if let Some(skip_draft) = input.source.skip_draft {
 		if skip_draft {
@@ -348,6 +538,45 @@ fn main() -> Result<()> {
 		eprintln!("  - Skip draft: Not specified (include all)");
 	}
 
+	// A single allowed user can be filtered by GitLab, anything else is filtered below
+	let single = |users: &Option<Vec<String>>| match users.as_deref() {
+		Some([user]) => Some(user.clone()),
+		_ => None,
+	};
+	if let Some(author) = single(&input.source.authors) {
+		eprintln!("  - Author: {}", author);
+		match author.parse::<u64>() {
+			Ok(id) => builder.author(id),
+			Err(_) => builder.author(author.trim_start_matches('@').to_owned()),
+		};
+	}
+	if let Some(id) = single(&input.source.assignees).and_then(|assignee| assignee.parse::<u64>().ok()) {
+		eprintln!("  - Assignee ID: {}", id);
+		builder.assignee_id(id);
+	}
+	if let Some(reviewer) = single(&input.source.reviewers) {
+		eprintln!("  - Reviewer: {}", reviewer);
+		match reviewer.parse::<u64>() {
+			Ok(id) => builder.reviewer(id),
+			Err(_) => builder.reviewer(reviewer.trim_start_matches('@').to_owned()),
+		};
+	}
+	match input.source.milestone.as_deref() {
+		Some("None") => {
+			eprintln!("  - Milestone: None");
+			builder.without_milestone();
+		},
+		Some("Any") => {
+			eprintln!("  - Milestone: Any");
+			builder.any_milestone();
+		},
+		Some(milestone) => {
+			eprintln!("  - Milestone: {}", milestone);
+			builder.milestone(milestone);
+		},
+		None => {},
+	}
+
 	if let Some(paths) = &input.source.paths {
 		eprintln!("  - Path filters: {:?}", paths);
 	} else {
@@ -356,10 +585,31 @@ fn main() -> Result<()> {
 
 	// Use pagination to get all results (GitLab limits to 100 per page by default)
 	eprintln!("Querying GitLab API for merge requests...");
-	let mrs: Vec<MergeRequest> = paged(builder.build()?, Pagination::All)
-		.query(&client)?;
+	let mut mrs = Vec::<MergeRequest>::new();
+	for state in states {
+		builder.state(state);
+		if let Some(group) = &input.source.group {
+			let endpoint = endpoints::GroupMergeRequests {
+				group: group.as_str().into(),
+				filters: builder.build()?,
+			};
+			let found: Vec<MergeRequest> = paged(endpoint, Pagination::All).query(&client)?;
+			mrs.extend(found);
+		} else if let Some(projects) = &input.source.projects {
+			for project in projects {
+				builder.project(project.as_str());
+				let found: Vec<MergeRequest> = paged(builder.build()?, Pagination::All)
+					.query(&client)?;
+				mrs.extend(found);
+			}
+		} else {
+			let found: Vec<MergeRequest> = paged(builder.build()?, Pagination::All)
+				.query(&client)?;
+			mrs.extend(found);
+		}
+	}
 
-	eprintln!("Found {} opened merge requests from GitLab API", mrs.len());
+	eprintln!("Found {} merge requests from GitLab API", mrs.len());
 	
 	if mrs.is_empty() {
 		eprintln!("No merge requests returned from GitLab API. This could mean:");
@@ -371,6 +621,49 @@ fn main() -> Result<()> {
 		return Ok(());
 	}
 
+	let mut state = CheckState::load();
+
+	// Rebuild commands: notes posted since the previous check (with a margin for clock skew) which are not consumed yet.
+	// A state without previous check (first run, state lost) triggers nothing, so that old commands never fire twice.
+	let check_start = Utc::now();
+	let rebuild_margin = chrono::Duration::seconds(60);
+	let rebuild_since = state
+		.last_check
+		.as_deref()
+		.and_then(|last_check| DateTime::<Utc>::from_str(last_check).ok())
+		.map_or(check_start, |last_check| last_check - rebuild_margin);
+	let mut rebuild_commands = match &input.source.rebuild_commands {
+		Some(commands) if !commands.is_empty() => {
+			let access_level = input.source.rebuild_access_level.as_deref().unwrap_or("developer");
+			eprintln!("Rebuild commands {:?} posted since {} by at least {}", commands, rebuild_since, access_level);
+			Some(triggers::RebuildCommands::new(commands, filters::parse_access_level(access_level)?.as_u64()))
+		},
+		_ => None,
+	};
+	let rebuild_label = input.source.rebuild_label.as_deref();
+	let remove_rebuild_label = input.source.remove_rebuild_label.unwrap_or(true);
+	if let Some(label) = rebuild_label {
+		eprintln!("Rebuild label: {} (removed by {})", label, if remove_rebuild_label { "check" } else { "out" });
+	}
+	let mut rebuild_versions = Vec::<Version>::new();
+	let mut consumed_notes = std::collections::HashMap::<u64, String>::new();
+	let mut rebuild_label_events = state.rebuild_label_events.clone();
+
+	let version_mode = input.source.version_mode.unwrap_or_default();
+	eprintln!("Version mode: {:?}", version_mode);
+	let current_key = input.version.as_ref().map(|v| v.commit_key());
+
+	let quiet_period = input.source.quiet_period_seconds.filter(|&seconds| seconds > 0).map(|seconds| {
+		eprintln!("Quiet period: {}s", seconds);
+		chrono::Duration::seconds(seconds.into())
+	});
+	let mut pending_shas = std::collections::HashMap::<String, String>::new();
+
+	// Create SHA-to-MR mapping for fast lookup during resurrection
+	// This allows us to check CI status only for MRs we're about to resurrect
+	use std::collections::HashMap;
+	let mut sha_to_mr: HashMap<String, &MergeRequest> = HashMap::new();
+
 	let mut all_versions = Vec::<Version>::new();
 	let mut processed_count = 0;
 	let mut skipped_count = 0;
@@ -383,7 +676,8 @@ fn main() -> Result<()> {
 		eprintln!("  Updated at: {}", mr.updated_at);
 		
 		// Check if SHA is null (happens when source branch is deleted)
-		let sha = match &mr.sha {
+		// Merged MRs are built at their merge commit instead (see MergeRequest::version_sha)
+		let sha = match mr.version_sha() {
 			Some(s) => s,
 			None => {
 				eprintln!("  ⚠️  WARNING: MR {} has null SHA (source branch likely deleted)", mr.iid);
@@ -394,10 +688,61 @@ fn main() -> Result<()> {
 		};
 		
 		let source_branch = mr.source_branch.as_deref().unwrap_or("<deleted>");
+		// The MR's own project, the watched ones may be many
+		let mr_project = mr.project_id.to_string();
+		let project_path = mr_project.as_str();
+		let version_project = multi_project.then(|| mr_project.clone());
 		
 		eprintln!("  SHA: {}", sha);
 		eprintln!("  Source branch: {}", source_branch);
 		eprintln!("  Labels: {:?}", mr.labels);
+
+		if let Some(reason) = filters.exclusion_reason(mr) {
+			eprintln!("  ❌ SKIPPED: {}", reason);
+			skipped_count += 1;
+			continue;
+		}
+		if let Some(reason) = filters.fork_reason(&client, project_path, mr)? {
+			eprintln!("  ❌ SKIPPED: {}", reason);
+			skipped_count += 1;
+			continue;
+		}
+
+		// Closed events mode: one version per closure, dated when the MR was closed or merged
+		if closed_events {
+			let closed_at = if mr.is_merged() { &mr.merged_at } else { &mr.closed_at };
+			let Some(closed_at) = closed_at else {
+				eprintln!("  ❌ SKIPPED: MR {} is {} without a closing date", mr.iid, mr.state);
+				skipped_count += 1;
+				continue;
+			};
+			eprintln!("  ✅ INCLUDING closure of MR {} ({} at {})", mr.iid, mr.state, closed_at);
+			all_versions.push(Version {
+				iid: mr.iid.to_string(),
+				committed_date: closed_at.clone(),
+				sha: sha.clone(),
+				trigger: None,
+				kind: Some(mr.state.clone()),
+				project: version_project,
+			});
+			processed_count += 1;
+			continue;
+		}
+
+		// Unapproved MRs are skipped without being returned, so they are returned as new versions once approved
+		if filters::requires_approvals(&input.source) {
+			let approvals: Approvals = MergeRequestApprovals::builder()
+				.project(project_path)
+				.merge_request(mr.iid)
+				.build()?
+				.query(&client)?;
+			if let Some(reason) = filters::approval_reason(&input.source, &approvals) {
+				eprintln!("  ❌ SKIPPED: {}", reason);
+				skipped_count += 1;
+				continue;
+			}
+			eprintln!("  ✅ Approved by {} user(s)", approvals.approved_by.len());
+		}
 		
 		// Apply path filtering if specified (before fetching commit to save API calls)
 		if let Some(paths) = &input.source.paths {
@@ -445,13 +790,19 @@ fn main() -> Result<()> {
 		// Get the commit information for the MR
 		eprintln!("  Fetching commit details for SHA {}...", sha);
 		let commit: Commit = commits::Commit::builder()
-			.project(mr.source_project_id)
+			.project(mr.version_project_id())
 			.commit(sha)
 			.build()?
 			.query(&client)?;
 
 		eprintln!("  Commit details:");
 		eprintln!("    Committed date: {}", commit.committed_date);
+
+		if let Some(reason) = filters::skip_ci_reason(&input.source, &commit) {
+			eprintln!("  ❌ SKIPPED: {}", reason);
+			skipped_count += 1;
+			continue;
+		}
 		
 		// CRITICAL FIX: Age filtering based on MR updated_at (not commit date)
 		// 
@@ -494,16 +845,99 @@ fn main() -> Result<()> {
 		// - MR #2726 has iid="2726", committed_date="2025-09-17", sha="abc123"
 		// - Even if MR #2500 had the same commit date, it has iid="2500" → different version
 		// - Concourse compares full objects: {"iid":"2726",...} ≠ {"iid":"2500",...} → triggers build ✅
+		// Merged MRs use their merge time: the merge commit may keep the date of the squashed/fast-forwarded commit
+		let committed_date = match (mr.is_merged(), &mr.merged_at) {
+			(true, Some(merged_at)) => merged_at.clone(),
+			_ => commit.committed_date.clone(), // ← Use actual commit date
+		};
 		let version = Version {
 			iid: mr.iid.to_string(),
-			committed_date: commit.committed_date.clone(), // ← Use actual commit date
+			committed_date,
 			sha: sha.clone(),
+			trigger: None,
+			kind: None,
+			project: version_project,
 		};
 
+		// Quiet period: a new head is held back until its commit is old enough, so that a burst of pushes is built
+		// once, at its last commit. Versions returned before are never held back.
+		if let Some(quiet_period) = quiet_period {
+			let key = version.commit_key();
+			let settles_at = settles_at(&version.committed_date, quiet_period)?;
+			if check_start < settles_at && !state.was_returned(&key) && current_key.as_ref() != Some(&key) {
+				let since = state.pending_shas.get(&key).cloned().unwrap_or_else(|| check_start.to_rfc3339());
+				eprintln!("  ⏳ HELD BACK: {} pending since {}, settles at {}", sha, since, settles_at);
+				pending_shas.insert(key, since);
+				skipped_count += 1;
+				continue;
+			}
+		}
+
+		// Every-commit mode: the commits pushed since the last one returned for this MR as well, oldest first.
+		// A head returned before (or current) has no new commits before it, no need to list them.
+		let head_key = version.commit_key();
+		let new_head = !state.was_returned(&head_key) && current_key.as_ref() != Some(&head_key);
+		if version_mode == VersionMode::EveryCommit && !mr.is_merged() && new_head {
+			let commits: Vec<Commit> = paged(
+				MergeRequestCommits::builder().project(project_path).merge_request(mr.iid).build()?,
+				Pagination::All,
+			)
+			.query(&client)?;
+			let known = |sha: &str| {
+				let key = commit_key(version.project.as_deref(), sha);
+				state.was_returned(&key) || current_key.as_ref() == Some(&key)
+			};
+			for commit in commits_since_known(&commits, sha, known) {
+				if let Some(reason) = filters::skip_ci_reason(&input.source, commit) {
+					eprintln!("  ⏭️  Skipping commit {}: {}", commit.id, reason);
+					continue;
+				}
+				eprintln!("  ✅ INCLUDING earlier commit {} ({})", commit.id, commit.committed_date);
+				let earlier = Version {
+					committed_date: commit.committed_date.clone(),
+					sha: commit.id.clone(),
+					..version.clone()
+				};
+				sha_to_mr.insert(earlier.commit_key(), mr);
+				all_versions.push(earlier);
+			}
+		}
+
 		eprintln!("  ✅ INCLUDING MR {} in candidate versions", mr.iid);
 		eprintln!("    Commit date: {} (used as committed_date)", commit.committed_date);
 		eprintln!("    MR updated: {} (not used - prevents comment loops)", mr.updated_at);
 
+		let mut trigger = None;
+		if let Some(rebuild_commands) = &mut rebuild_commands {
+			let notes = rebuild_commands.find(&client, project_path, mr, rebuild_since, &state.consumed_notes)?;
+			if let Some(note) = notes.last() {
+				eprintln!("  🔁 REBUILD requested by `{}` in note {}", note.author.username, note.id);
+				trigger = Some(format!("note:{}", note.id));
+			}
+			consumed_notes.extend(notes.into_iter().map(|note| (note.id, note.created_at)));
+		}
+		if let Some(label) = rebuild_label {
+			let event = triggers::label_event(&client, project_path, mr, label)?;
+			let event_id = event.as_ref().map(|event| event.id);
+			if consume_label_event(&mut rebuild_label_events, version.mr_key(), event_id) {
+				let event_id = event_id.unwrap_or_default();
+				eprintln!("  🔁 REBUILD requested by label `{}` (event {})", label, event_id);
+				trigger.get_or_insert_with(|| format!("label:{}", event_id));
+				if remove_rebuild_label {
+					if let Err(e) = triggers::remove_label(&client, project_path, mr, label) {
+						eprintln!("  ⚠️  Failed to remove label `{}`: {}", label, e);
+					}
+				}
+			}
+		}
+		if let Some(trigger) = trigger {
+			rebuild_versions.push(Version {
+				trigger: Some(trigger),
+				..version.clone()
+			});
+		}
+
+		sha_to_mr.insert(version.commit_key(), mr);
 		all_versions.push(version);
 		processed_count += 1;
 	}
@@ -514,14 +948,8 @@ fn main() -> Result<()> {
 	eprintln!("Skipped due to filters: {}", skipped_count);
 	eprintln!("Candidate versions before final filtering: {}", all_versions.len());
 
-	// Create SHA-to-MR mapping for fast lookup during resurrection
-	// This allows us to check CI status only for MRs we're about to resurrect
-	use std::collections::HashMap;
-	let mut sha_to_mr: HashMap<String, &MergeRequest> = HashMap::new();
-	for mr in &mrs {
-		if let Some(ref sha) = mr.sha {
-			sha_to_mr.insert(sha.clone(), mr);
-		}
+	if closed_events {
+		return emit_closures(all_versions, input.version.is_none(), cutoff_date, state);
 	}
 
 	// Sort versions by committed_date ascending (oldest first) for Concourse
@@ -548,8 +976,8 @@ fn main() -> Result<()> {
 			let current_dt = DateTime::<Utc>::from_str(&current_version.committed_date)?;
 			let is_newer = candidate_dt > current_dt;
 			let is_same_time = candidate_dt == current_dt;
-			let is_different_mr = version.iid != current_version.iid;
-			let is_current_mr = version.iid == current_version.iid;
+			let is_different_mr = version.mr_key() != current_version.mr_key();
+			let is_current_mr = version.mr_key() == current_version.mr_key();
 			
 			// Include MR if:
 			// 1. Is the current MR itself (Concourse contract - always include current)
@@ -589,14 +1017,20 @@ fn main() -> Result<()> {
 		// SMART MR-AWARE FILTERING:
 		// Group by MR IID and keep only the latest commit per MR
 		// This allows parallel builds for different MRs while avoiding redundant builds for old commits
+		// In every-commit mode each commit is its own group, so that every pushed commit is built
 		eprintln!("\n=== SMART MR-AWARE FILTERING ===");
-		eprintln!("Grouping {} versions by MR IID (keeping only latest commit per MR):", newer_versions.len());
+		let group_key = |version: &Version| match version_mode {
+			VersionMode::LatestPerMr => version.mr_key(),
+			VersionMode::EveryCommit => version.commit_key(),
+		};
+		eprintln!("Grouping {} versions by {} (keeping only latest commit per group):",
+			newer_versions.len(),
+			if version_mode == VersionMode::EveryCommit { "commit" } else { "MR IID" });
 		
-		use std::collections::HashMap;
 		let mut mr_latest: HashMap<String, Version> = HashMap::new();
 		
 		for version in newer_versions {
-			let iid = version.iid.clone();
+			let iid = group_key(&version);
 			
 			// Check if we already have a version for this MR
 			if let Some(existing) = mr_latest.get(&iid) {
@@ -617,11 +1051,10 @@ fn main() -> Result<()> {
 		}
 		
 		// Always ensure current version is included (Concourse contract)
-		let current_iid = &current_version.iid;
-		if !mr_latest.contains_key(current_iid) {
+		if let std::collections::hash_map::Entry::Vacant(entry) = mr_latest.entry(group_key(current_version)) {
 			eprintln!("\n⚠️  Adding current version back (required by Concourse contract)");
-			eprintln!("  MR #{}: {}", current_iid, current_version.committed_date);
-			mr_latest.insert(current_iid.clone(), current_version.clone());
+			eprintln!("  MR #{}: {}", entry.key(), current_version.committed_date);
+			entry.insert(current_version.clone());
 		}
 		
 		// Convert HashMap back to Vec and sort by committed_date
@@ -684,8 +1117,7 @@ fn main() -> Result<()> {
 	// 
 	eprintln!("\n=== STATE-BASED FILTERING (SOLUTION #1) ===");
 	
-	// Load existing state
-	let mut state = CheckState::load();
+	// Existing state was loaded before processing MRs (rebuild commands need it)
 	
 	// NO MIGRATION - Let resurrection mode handle stuck MRs!
 	// Migration was the wrong approach because:
@@ -794,13 +1226,13 @@ fn main() -> Result<()> {
 	}
 	
 	// Check if any filtered versions are stuck (only if resurrection enabled)
-	let current_sha = input.version.as_ref().map(|v| v.sha.as_str());
+	let current_sha = input.version.as_ref().map(|v| v.commit_key());
 	
 	if resurrection_enabled {
 		for version in &filtered_versions {
-			if state.was_returned(&version.sha) && Some(version.sha.as_str()) != current_sha {
+			if state.was_returned(&version.commit_key()) && Some(version.commit_key()) != current_sha {
 				// Check if already resurrected before
-				if state.was_resurrected(&version.sha) {
+				if state.was_resurrected(&version.commit_key()) {
 					eprintln!("  ⏭️  MR #{} (SHA: {}) was ALREADY resurrected before - skipping", version.iid, version.sha);
 				} else {
 					eprintln!("  🔍 MR #{} (SHA: {}) was returned before but is NOT current", version.iid, version.sha);
@@ -821,7 +1253,7 @@ fn main() -> Result<()> {
 	// 1. Concourse already has it (it's the "current" version)
 	// 2. Future checks need to see it to determine what's newer
 	// 3. Filtering it out breaks Concourse's scheduler
-	let current_sha = input.version.as_ref().map(|v| v.sha.as_str());
+	let current_sha = input.version.as_ref().map(|v| v.commit_key());
 	
 	// Separate versions into: new, stuck (need resurrection), and current
 	let mut new_versions = Vec::new();
@@ -830,16 +1262,20 @@ fn main() -> Result<()> {
 	
 	for version in filtered_versions {
 		// NEVER filter out the current version (Concourse needs to see it)
-		if Some(version.sha.as_str()) == current_sha {
+		if Some(version.commit_key()) == current_sha {
 			eprintln!("  ⭐ Keeping MR #{} (SHA: {}) - current version (required by Concourse)", version.iid, version.sha);
-			new_versions.push(version);
+			// A requested rebuild stays current until the next commit
+			match &input.version {
+				Some(current) if current.trigger.is_some() && current.mr_key() == version.mr_key() => new_versions.push(current.clone()),
+				_ => new_versions.push(version),
+			}
 			continue;
 		}
 		
-		let was_returned = state.was_returned(&version.sha);
+		let was_returned = state.was_returned(&version.commit_key());
 		
 		// Only resurrect if: 1) was returned before, 2) not current, 3) resurrection enabled, 4) NOT already resurrected
-		if was_returned && resurrection_enabled && !state.was_resurrected(&version.sha) {
+		if was_returned && resurrection_enabled && !state.was_resurrected(&version.commit_key()) {
 			// This version was returned before but is NOT current
 			// It's STUCK in Concourse DB with low check_order
 			eprintln!("  🔍 MR #{} (SHA: {}) was returned before but is NOT current", version.iid, version.sha);
@@ -851,13 +1287,13 @@ fn main() -> Result<()> {
 			// If an MR has CI status, it was already built - DO NOT RESURRECT IT.
 			eprintln!("     Checking if stuck MR has CI status before resurrecting...");
 			
-			// Look up the MR to get its source_project_id
+			// Look up the MR to get the project of the commit
 			let mut has_ci_status = false;
-			if let Some(mr) = sha_to_mr.get(&version.sha) {
+			if let Some(mr) = sha_to_mr.get(&version.commit_key()) {
 				// Query GitLab API for commit statuses
 				let statuses_result: Result<Vec<CommitStatus>, _> = paged(
 					CommitStatuses::builder()
-						.project(mr.source_project_id)
+						.project(mr.version_project_id())
 						.commit(&version.sha)
 						.build()?,
 					Pagination::Limit(1), // We only need to know if ANY status exists
@@ -907,6 +1343,9 @@ fn main() -> Result<()> {
 				iid: version.iid.clone(),
 				committed_date: resurrection_date,
 				sha: version.sha.clone(),
+				trigger: None,
+				kind: None,
+				project: version.project.clone(),
 			};
 			
 			// CRITICAL: Track original SHA for state saving
@@ -914,7 +1353,7 @@ fn main() -> Result<()> {
 			// - Next check will fetch same MR from GitLab with real date
 			// - We need to filter it out (already resurrected once)
 			// - If we saved fake SHA, we wouldn't recognize the real one
-			resurrected_shas.push(version.sha.clone());
+			resurrected_shas.push(version.commit_key());
 			resurrected_versions.push(resurrected);
 		} else if was_returned {
 			// Was returned before, but resurrection is DISABLED
@@ -945,8 +1384,8 @@ fn main() -> Result<()> {
 	// We need to save these to state, but NOT the resurrected ones
 	let new_shas_to_save: Vec<String> = new_versions
 		.iter()
-		.filter(|v| Some(v.sha.as_str()) != current_sha)
-		.map(|v| v.sha.clone())
+		.filter(|v| Some(v.commit_key()) != current_sha)
+		.map(|v| v.commit_key())
 		.collect();
 	
 	// Combine: resurrected first (fake old date sorts first), then new versions
@@ -956,6 +1395,12 @@ fn main() -> Result<()> {
 	
 	// Re-sort by committed_date to ensure proper ordering
 	final_versions.sort_by(|a, b| a.committed_date.cmp(&b.committed_date));
+
+	// Requested rebuilds come last, so that Concourse builds them even though their commits were built before
+	if !rebuild_versions.is_empty() {
+		eprintln!("Appending {} requested rebuild(s)", rebuild_versions.len());
+		final_versions.extend(rebuild_versions);
+	}
 	
 	eprintln!("\nPost-filter: {} versions to return", final_versions.len());
 	
@@ -980,8 +1425,26 @@ fn main() -> Result<()> {
 		}
 	}
 	
-	// Save state if anything changed (new SHAs or resurrected SHAs)
-	if !new_shas_to_save.is_empty() || !resurrected_shas.is_empty() {
+	if rebuild_commands.is_some() {
+		let last_check = next_last_check(state.last_check.as_deref(), check_start, !pending_shas.is_empty());
+		let next_since =
+			DateTime::<Utc>::from_str(&last_check).map_or(check_start, |last_check| last_check - rebuild_margin);
+		state.consumed_notes = remember_notes(&state.consumed_notes, consumed_notes, next_since);
+		state.last_check = Some(last_check);
+	}
+	if rebuild_label.is_some() {
+		state.rebuild_label_events = rebuild_label_events;
+	}
+
+	let pending_changed = pending_shas != state.pending_shas;
+	if pending_changed {
+		eprintln!("Holding back {} pending SHA(s) (was {})", pending_shas.len(), state.pending_shas.len());
+		state.pending_shas = pending_shas;
+	}
+
+	// Save state if anything changed (new SHAs, resurrected SHAs, pending SHAs or rebuild triggers)
+	let rebuild_enabled = rebuild_commands.is_some() || rebuild_label.is_some();
+	if !new_shas_to_save.is_empty() || !resurrected_shas.is_empty() || pending_changed || rebuild_enabled {
 		// Save state (non-fatal if fails)
 		if let Err(e) = state.save() {
 			eprintln!("⚠️  Warning: Failed to save state: {}", e);
@@ -1007,7 +1470,13 @@ fn main() -> Result<()> {
 		eprintln!("📬 RETURNING VERSIONS:");
 		for (i, version) in final_versions.iter().enumerate() {
 			let is_resurrected = version.committed_date == "2099-12-31T23:59:59Z";
-			let marker = if is_resurrected { "🚑 RESURRECTED" } else { "✅ NEW" };
+			let marker = if is_resurrected {
+				"🚑 RESURRECTED"
+			} else if version.trigger.is_some() {
+				"🔁 REBUILD"
+			} else {
+				"✅ NEW"
+			};
 			eprintln!("  {}. {} - MR #{} - committed: {} - SHA: {}", 
 				i + 1, marker, version.iid, version.committed_date, version.sha);
 		}
@@ -763,3 +763,243 @@ mod check_filtering_tests {
         assert_eq!(result, mrs);
     }
 }
+
+#[cfg(test)]
+mod closed_events_tests {
+    use crate::{select_closures, Version};
+    use chrono::{DateTime, Utc};
+    use std::collections::HashSet;
+
+    fn cutoff() -> DateTime<Utc> {
+        "2024-12-01T00:00:00Z".parse().unwrap()
+    }
+
+    fn closure(iid: u64, kind: &str, date: &str) -> Version {
+        Version {
+            iid: iid.to_string(),
+            committed_date: date.to_string(),
+            sha: format!("sha{}", iid),
+            trigger: None,
+            kind: Some(kind.to_string()),
+            project: None,
+        }
+    }
+
+    #[test]
+    fn test_first_check_returns_latest_closure_only() {
+        let closures = vec![
+            closure(2, "merged", "2025-01-02T00:00:00Z"),
+            closure(1, "closed", "2025-01-01T00:00:00Z"),
+        ];
+        let (new, emitted) = select_closures(closures, true, &HashSet::new(), cutoff());
+
+        assert_eq!(new, vec![closure(2, "merged", "2025-01-02T00:00:00Z")]);
+        assert_eq!(emitted.len(), 2);
+    }
+
+    #[test]
+    fn test_closures_are_returned_once() {
+        let first = vec![closure(1, "closed", "2025-01-01T00:00:00Z")];
+        let (_, emitted) = select_closures(first, true, &HashSet::new(), cutoff());
+
+        // MR 1 reopened and closed again, MR 3 merged
+        let closures = vec![
+            closure(1, "closed", "2025-01-01T00:00:00Z"),
+            closure(3, "merged", "2025-01-03T00:00:00Z"),
+            closure(1, "closed", "2025-01-04T00:00:00Z"),
+        ];
+        let (new, emitted) = select_closures(closures, false, &emitted, cutoff());
+
+        assert_eq!(
+            new,
+            vec![closure(3, "merged", "2025-01-03T00:00:00Z"), closure(1, "closed", "2025-01-04T00:00:00Z")]
+        );
+        assert_eq!(emitted.len(), 3);
+    }
+
+    #[test]
+    fn test_closures_of_projects_are_distinct() {
+        let in_project = |project: &str| Version {
+            project: Some(project.to_string()),
+            ..closure(1, "merged", "2025-01-01T00:00:00Z")
+        };
+        let (new, emitted) = select_closures(vec![in_project("7")], false, &HashSet::new(), cutoff());
+        assert_eq!(new.len(), 1);
+
+        let (new, _) = select_closures(vec![in_project("7"), in_project("8")], false, &emitted, cutoff());
+        assert_eq!(new, vec![in_project("8")]);
+    }
+
+    #[test]
+    fn test_closure_not_fetched_for_a_while_is_not_returned_again() {
+        let first = vec![closure(1, "closed", "2025-01-01T00:00:00Z")];
+        let (_, emitted) = select_closures(first, false, &HashSet::new(), cutoff());
+
+        // `updated_after` moved past MR 1: only MR 2 is fetched
+        let later = vec![closure(2, "merged", "2025-01-02T00:00:00Z")];
+        let (new, emitted) = select_closures(later, false, &emitted, cutoff());
+        assert_eq!(new, vec![closure(2, "merged", "2025-01-02T00:00:00Z")]);
+
+        // A comment on MR 1 brings it back
+        let closures = vec![closure(1, "closed", "2025-01-01T00:00:00Z"), closure(2, "merged", "2025-01-02T00:00:00Z")];
+        let (new, _) = select_closures(closures, false, &emitted, cutoff());
+        assert!(new.is_empty());
+    }
+
+    #[test]
+    fn test_closures_before_cutoff_are_forgotten() {
+        let closures = vec![closure(1, "closed", "2024-11-01T00:00:00Z"), closure(2, "closed", "2025-01-01T00:00:00Z")];
+        let (_, emitted) = select_closures(closures, false, &HashSet::new(), cutoff());
+        assert_eq!(emitted, HashSet::from(["2:closed:2025-01-01T00:00:00Z".to_string()]));
+    }
+}
+
+#[cfg(test)]
+mod every_commit_tests {
+    use crate::{commits_since_known, Commit};
+
+    fn commits(ids: &[&str]) -> Vec<Commit> {
+        ids.iter()
+            .map(|id| Commit {
+                id: id.to_string(),
+                committed_date: "2025-01-01T00:00:00Z".to_string(),
+                message: String::new(),
+            })
+            .collect()
+    }
+
+    fn ids(commits: Vec<&Commit>) -> Vec<&str> {
+        commits.into_iter().map(|commit| commit.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_commits_since_last_known_oldest_first() {
+        let commits = commits(&["d", "c", "b", "a"]);
+        assert_eq!(ids(commits_since_known(&commits, "d", |sha| sha == "a")), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_all_earlier_commits_without_known_one() {
+        let commits = commits(&["c", "b", "a"]);
+        assert_eq!(ids(commits_since_known(&commits, "c", |_| false)), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_nothing_when_previous_commit_known_or_head_missing() {
+        let commits = commits(&["c", "b", "a"]);
+        assert!(commits_since_known(&commits, "c", |sha| sha == "b").is_empty());
+        assert!(commits_since_known(&commits, "x", |_| false).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod quiet_period_tests {
+    use crate::settles_at;
+
+    #[test]
+    fn test_settles_after_commit() {
+        let quiet = chrono::Duration::seconds(120);
+        let at = |date: &str| date.parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+
+        assert_eq!(settles_at("2025-01-01T10:00:00Z", quiet).unwrap(), at("2025-01-01T10:02:00Z"));
+        assert_eq!(settles_at("2025-01-01T10:00:00+02:00", quiet).unwrap(), at("2025-01-01T08:02:00Z"));
+        assert!(settles_at("not a date", quiet).is_err());
+    }
+}
+
+#[cfg(test)]
+mod rebuild_label_tests {
+    use crate::consume_label_event;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_label_triggers_once_until_added_again() {
+        let mut events = HashMap::new();
+
+        assert!(consume_label_event(&mut events, "1".to_string(), Some(10)));
+        assert!(!consume_label_event(&mut events, "1".to_string(), Some(10)));
+        // Removed, then added again
+        assert!(!consume_label_event(&mut events, "1".to_string(), None));
+        assert!(consume_label_event(&mut events, "1".to_string(), Some(11)));
+    }
+
+    #[test]
+    fn test_unseen_mr_keeps_its_event() {
+        let mut events = HashMap::new();
+        assert!(consume_label_event(&mut events, "1".to_string(), Some(10)));
+
+        // MR 1 is not fetched by a few checks (only MR 2 is), then updated with the label still on it
+        assert!(consume_label_event(&mut events, "2".to_string(), Some(20)));
+        assert!(!consume_label_event(&mut events, "1".to_string(), Some(10)));
+    }
+}
+
+#[cfg(test)]
+mod rebuild_notes_tests {
+    use crate::{next_last_check, remember_notes};
+    use chrono::{DateTime, Duration, Utc};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    /// The notes a check with the given window finds, like `RebuildCommands::find`
+    fn find(notes: &[(u64, &str)], since: DateTime<Utc>, consumed: &HashMap<u64, String>) -> HashMap<u64, String> {
+        notes
+            .iter()
+            .filter(|(id, created_at)| {
+                DateTime::<Utc>::from_str(created_at).unwrap() >= since && !consumed.contains_key(id)
+            })
+            .map(|(id, created_at)| (*id, created_at.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_note_triggers_once_over_three_checks() {
+        let margin = Duration::seconds(60);
+        let notes = [(1, "2024-01-01T12:00:00Z")];
+        let note_time = DateTime::<Utc>::from_str(notes[0].1).unwrap();
+        let mut consumed = HashMap::new();
+        let mut since = note_time - Duration::seconds(30);
+
+        let mut triggered = vec![];
+        for check_start in [10, 40, 50].map(|seconds| note_time + Duration::seconds(seconds)) {
+            let found = find(&notes, since, &consumed);
+            triggered.extend(found.keys().copied());
+            consumed = remember_notes(&consumed, found, check_start - margin);
+            since = check_start - margin;
+        }
+        assert_eq!(triggered, vec![1]);
+    }
+
+    #[test]
+    fn test_note_on_held_back_mr_is_found_once_it_settles() {
+        let margin = Duration::seconds(60);
+        let start = DateTime::<Utc>::from_str("2024-01-01T12:00:00Z").unwrap();
+        let notes = [(1, "2024-01-01T12:00:05Z")];
+        let mut consumed = HashMap::new();
+        let mut last_check: Option<String> = None;
+
+        let mut triggered = vec![];
+        // The MR is held back by the first two checks, so they do not look for its notes
+        for (seconds, held_back) in [(0, true), (70, true), (130, false), (190, false)] {
+            let check_start = start + Duration::seconds(seconds);
+            let since = last_check
+                .as_deref()
+                .map_or(check_start, |last_check| DateTime::<Utc>::from_str(last_check).unwrap() - margin);
+            let found = if held_back { HashMap::new() } else { find(&notes, since, &consumed) };
+            triggered.extend(found.keys().copied());
+            let next = next_last_check(last_check.as_deref(), check_start, held_back);
+            consumed = remember_notes(&consumed, found, DateTime::<Utc>::from_str(&next).unwrap() - margin);
+            last_check = Some(next);
+        }
+        assert_eq!(triggered, vec![1]);
+    }
+
+    #[test]
+    fn test_notes_before_the_window_are_forgotten() {
+        let previous = HashMap::from([(1, "2024-01-01T12:00:00Z".to_string())]);
+        let consumed = HashMap::from([(2, "2024-01-01T12:05:00Z".to_string())]);
+        let next_since = DateTime::<Utc>::from_str("2024-01-01T12:01:00Z").unwrap();
+        let remembered = remember_notes(&previous, consumed, next_since);
+        assert_eq!(remembered.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+}
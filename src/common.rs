@@ -33,12 +33,41 @@ pub struct CommitStatus {
 	pub status: String,
 	pub name: Option<String>,
 	pub description: Option<String>,
+	#[serde(rename = "ref")]
+	pub ref_name: Option<String>,
+	pub target_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct Commit {
+	pub id: String,
 	pub committed_date: String,
+	#[serde(default)]
+	pub message: String,
+}
+
+impl Commit {
+	/// The trailers (`Key: value` lines of the last paragraph) of the commit message, in order.
+	#[allow(dead_code)]
+	pub fn trailers(&self) -> Vec<(String, String)> {
+		let Some(paragraph) = self.message.trim_end().rsplit("\n\n").next() else {
+			return Vec::new();
+		};
+		let trailers: Vec<(String, String)> = paragraph
+			.lines()
+			.filter_map(|line| {
+				let (key, value) = line.split_once(':')?;
+				let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+				is_key.then(|| (key.to_owned(), value.trim().to_owned()))
+			})
+			.collect();
+		/* a paragraph with other lines, or the subject alone, is not a trailer block */
+		if trailers.len() != paragraph.lines().count() || !self.message.trim().contains("\n\n") {
+			return Vec::new();
+		}
+		trailers
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,10 +77,35 @@ pub struct Project {
 	pub ssh_url_to_repo: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Environment {
+	pub id: u64,
+	pub name: String,
+	pub external_url: Option<String>,
+	pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Deployment {
+	pub id: u64,
+	pub sha: String,
+	pub status: String,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct Author {
+	pub id: u64,
 	pub name: String,
+	pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Milestone {
+	pub title: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -79,20 +133,196 @@ pub struct Diff {
 	pub deleted_file: bool,
 }
 
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct DiffRefs {
+	pub base_sha: String,
+	pub head_sha: String,
+	pub start_sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Note {
+	pub id: u64,
+	pub body: String,
+	pub author: Author,
+	pub created_at: String,
+	/// Notes generated by GitLab, such as "added 1 commit"
+	#[serde(default)]
+	pub system: bool,
+	/// Only set on notes which can be resolved
+	pub resolved: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Approver {
+	pub user: Author,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Approvals {
+	/// Whether the approval rules of the project are satisfied
+	pub approved: bool,
+	#[serde(default)]
+	pub approved_by: Vec<Approver>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Label {
+	pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct LabelEvent {
+	pub id: u64,
+	/// add or remove
+	pub action: String,
+	/// Null when the label was deleted
+	pub label: Option<Label>,
+	pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Discussion {
+	pub id: String,
+	pub notes: Vec<Note>,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct MergeRequest {
 	pub iid: u64,
 	pub title: String,
+	/// opened, closed, locked or merged
+	pub state: String,
 	pub labels: Vec<String>,
 	/// SHA can be null when the source branch is deleted but MR is still open
 	pub sha: Option<String>,
 	pub author: Author,
+	#[serde(default)]
+	pub assignees: Vec<Author>,
+	#[serde(default)]
+	pub reviewers: Vec<Author>,
+	pub milestone: Option<Milestone>,
 	pub updated_at: String,
+	/// The target project
+	pub project_id: u64,
 	pub source_project_id: u64,
 	/// Source branch can be null when the source branch is deleted
 	pub source_branch: Option<String>,
+	pub target_branch: String,
 	pub web_url: String,
+	/// Only returned when requested with `include_rebase_in_progress`
+	pub rebase_in_progress: Option<bool>,
+	pub merge_error: Option<String>,
+	/// Only returned for a single merge request
+	pub diff_refs: Option<DiffRefs>,
+	#[serde(default)]
+	pub has_conflicts: bool,
+	/// mergeable, conflict, need_rebase, ci_must_pass, checking...
+	pub detailed_merge_status: Option<String>,
+	/// Null for fast-forward merges
+	pub merge_commit_sha: Option<String>,
+	pub squash_commit_sha: Option<String>,
+	pub merged_at: Option<String>,
+	pub closed_at: Option<String>,
+}
+
+impl MergeRequest {
+	/// Whether the source branch lives in a fork of the target project.
+	#[allow(dead_code)]
+	pub fn is_fork(&self) -> bool {
+		self.source_project_id != self.project_id
+	}
+
+	#[allow(dead_code)]
+	pub fn is_merged(&self) -> bool {
+		self.state == "merged"
+	}
+
+	/// The commit to build: the head of the source branch, or what landed on the target branch once merged.
+	#[allow(dead_code)]
+	pub fn version_sha(&self) -> Option<&String> {
+		if self.is_merged() {
+			self.merge_commit_sha.as_ref().or(self.squash_commit_sha.as_ref()).or(self.sha.as_ref())
+		} else {
+			self.sha.as_ref()
+		}
+	}
+
+	/// The project containing `version_sha`.
+	#[allow(dead_code)]
+	pub fn version_project_id(&self) -> u64 {
+		if self.is_merged() {
+			self.project_id
+		} else {
+			self.source_project_id
+		}
+	}
+
+	/// Whether `sha` is the merged version of the MR (see `version_sha`), on its target branch, rather than a head
+	/// built before the merge.
+	#[allow(dead_code)]
+	pub fn is_merged_version(&self, sha: &str) -> bool {
+		self.is_merged() && self.version_sha().is_some_and(|version_sha| version_sha == sha)
+	}
+
+	/// The project containing the commit `sha` of a version of the MR, whatever its current state.
+	#[allow(dead_code)]
+	pub fn project_id_of(&self, sha: &str) -> u64 {
+		if self.is_merged_version(sha) {
+			self.project_id
+		} else {
+			self.source_project_id
+		}
+	}
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchedState {
+	/// Open MRs, built at the head of their source branch
+	#[default]
+	Opened,
+	/// Merged MRs, built at their merge commit
+	Merged,
+	Both,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionMode {
+	/// Only the latest commit of each MR
+	#[default]
+	LatestPerMr,
+	/// Every commit pushed to an MR
+	EveryCommit,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct Member {
+	pub id: u64,
+	pub username: String,
+	pub access_level: u64,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ForkPolicy {
+	/// Check fork MRs like any other MR
+	#[default]
+	Allow,
+	/// Never check fork MRs
+	Skip,
+	/// Only check fork MRs authored by trusted users
+	Trusted,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
@@ -100,16 +330,60 @@ pub struct Version {
 	pub iid: String,
 	pub committed_date: String,
 	pub sha: String,
+	/// What requested a rebuild of an already built commit (e.g. `note:1234`), so that Concourse sees a new version
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub trigger: Option<String>,
+	/// `closed` or `merged` for the closures returned in `closed_events` mode
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub kind: Option<String>,
+	/// ID of the project of the MR, when watching a group or several projects
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub project: Option<String>,
+}
+
+impl Version {
+	/// Identifies the MR of the version, IIDs are only unique within a project.
+	#[allow(dead_code)]
+	pub fn mr_key(&self) -> String {
+		match &self.project {
+			Some(project) => format!("{}!{}", project, self.iid),
+			None => self.iid.clone(),
+		}
+	}
+
+	/// Identifies the commit of the version in the check state.
+	#[allow(dead_code)]
+	pub fn commit_key(&self) -> String {
+		commit_key(self.project.as_deref(), &self.sha)
+	}
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+/// The SHA of a commit, prefixed with its project when watching a group or several projects.
+#[allow(dead_code)]
+pub fn commit_key(project: Option<&str>, sha: &str) -> String {
+	match project {
+		Some(project) => format!("{}@{}", project, sha),
+		None => sha.to_owned(),
+	}
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct Source {
 	pub uri: String,
 	pub private_token: String,
+	/// Only MRs with all of these labels (`team::*` wildcards are allowed)
 	pub labels: Option<Vec<String>>,
+	/// Only MRs with at least one of these labels
+	pub any_labels: Option<Vec<String>>,
+	/// Skip MRs with any of these labels
+	pub ignore_labels: Option<Vec<String>>,
 	pub paths: Option<Vec<String>>,
 	pub skip_draft: Option<bool>,
 	pub target_branch: Option<String>,
+	/// Only MRs whose target branch matches these globs or `/regexes/` (`!` negates)
+	pub target_branches: Option<Vec<String>>,
+	/// Only MRs whose source branch matches these globs or `/regexes/` (`!` negates)
+	pub source_branches: Option<Vec<String>>,
 	/// Maximum age in days for merge requests to be considered (default: 90 days / 3 months)
 	/// This filters based on MR's updated_at date (when the MR was last modified)
 	pub max_age_days: Option<u32>,
@@ -117,10 +391,70 @@ pub struct Source {
 	/// When comparing versions, include MRs with commits within this many days of current version.
 	/// This filters based on commit's committed_date (when the code was committed)
 	pub commit_date_window_days: Option<u32>,
+	/// Hold back a new head until it and the MR are unchanged for this long, to build rapid pushes once
+	pub quiet_period_seconds: Option<u32>,
 	/// Skip MRs where the last commit has any CI status (prevents rebuilding already-built MRs)
 	pub skip_mr_with_ci_status: Option<bool>,
 	/// Disable resurrection of stuck MRs (useful for multi-worker Kubernetes environments)
 	pub disable_resurrection: Option<bool>,
+	/// Only MRs authored by one of these users (usernames or numeric IDs)
+	pub authors: Option<Vec<String>>,
+	/// Skip MRs authored by one of these users
+	pub ignore_authors: Option<Vec<String>>,
+	/// Only MRs assigned to at least one of these users
+	pub assignees: Option<Vec<String>>,
+	/// Skip MRs assigned to any of these users
+	pub ignore_assignees: Option<Vec<String>>,
+	/// Only MRs with at least one of these users as reviewer
+	pub reviewers: Option<Vec<String>>,
+	/// Skip MRs with any of these users as reviewer
+	pub ignore_reviewers: Option<Vec<String>>,
+	/// Only MRs in this milestone (by title), or `None` / `Any`
+	pub milestone: Option<String>,
+	/// What to do with MRs from forks (default: allow)
+	pub fork_policy: Option<ForkPolicy>,
+	/// Authors of fork MRs trusted by the `trusted` fork policy (usernames or numeric IDs)
+	pub trusted_users: Option<Vec<String>>,
+	/// Groups (paths or numeric IDs) whose members are trusted by the `trusted` fork policy
+	pub trusted_groups: Option<Vec<String>>,
+	/// Minimum access level on the project trusted by the `trusted` fork policy (default: developer)
+	pub trusted_access_level: Option<String>,
+	/// Comments (e.g. `/retest`) which request a rebuild of the head commit of an MR
+	pub rebuild_commands: Option<Vec<String>>,
+	/// Minimum access level on the project of the authors of rebuild commands (default: developer)
+	pub rebuild_access_level: Option<String>,
+	/// Label (e.g. `ci::rebuild`) which requests a rebuild of the head commit of an MR
+	pub rebuild_label: Option<String>,
+	/// Remove the rebuild label in check once it triggered a rebuild (default: true)
+	pub remove_rebuild_label: Option<bool>,
+	/// Skip MRs whose head commit message contains one of these markers (default: `[ci skip]` and `[skip ci]`)
+	pub skip_ci_markers: Option<Vec<String>>,
+	/// Only MRs which satisfy the approval rules of the project
+	pub require_approved: Option<bool>,
+	/// Only MRs approved by at least this many users
+	pub min_approvals: Option<usize>,
+	/// Skip MRs with merge conflicts
+	pub skip_conflicted: Option<bool>,
+	/// Only MRs whose `detailed_merge_status` is one of these
+	pub merge_status_allow: Option<Vec<String>>,
+	/// Which MRs to check: opened (default), merged or both
+	pub state: Option<WatchedState>,
+	/// Return one version per MR closed or merged instead, e.g. to tear down review environments
+	pub closed_events: Option<bool>,
+	/// Return the latest commit of each MR (default) or every pushed commit
+	pub version_mode: Option<VersionMode>,
+	/// Watch the MRs of all projects of this group (and its subgroups) instead of the project of `uri`
+	pub group: Option<String>,
+	/// Watch the MRs of these projects instead of the project of `uri`
+	pub projects: Option<Vec<String>>,
+}
+
+impl Source {
+	/// Whether MRs of several projects are watched, so that versions need their project.
+	#[allow(dead_code)]
+	pub fn is_multi_project(&self) -> bool {
+		self.group.is_some() || self.projects.is_some()
+	}
 }
 
 pub fn get_data_from<T: for<'de> Deserialize<'de>>(stdin: &mut impl io::Read) -> Result<T, Box<dyn error::Error>> {
@@ -133,7 +467,9 @@ pub fn get_data_from<T: for<'de> Deserialize<'de>>(stdin: &mut impl io::Read) ->
 mod tests {
 	use super::{
 		get_data_from,
+		Commit,
 		Deserialize,
+		MergeRequest,
 		Source,
 		Version,
 	};
@@ -160,17 +496,82 @@ mod tests {
 				source: Source {
 					uri: "https://gitlab.com/cheatsc/test.git".to_owned(),
 					private_token: "zzzzz".to_owned(),
-					labels: None,
-					paths: None,
-					skip_draft: None,
-					target_branch: None,
-					max_age_days: None,
-					commit_date_window_days: None,
-					skip_mr_with_ci_status: None,
-					disable_resurrection: None,
+					..Default::default()
 				},
 				version: None,
 			}
 		);
 	}
+
+	#[test]
+	fn test_trailers() {
+		let commit = |message: &str| Commit {
+			id: "abc".to_owned(),
+			committed_date: "2025-01-01T00:00:00Z".to_owned(),
+			message: message.to_owned(),
+		};
+		assert_eq!(
+			commit("Fix lint\n\nBody: not a trailer\nsince this line is prose.\n\nConcourse-Skip: lint\nSigned-off-by: A <a@b.c>\n")
+				.trailers(),
+			vec![
+				("Concourse-Skip".to_owned(), "lint".to_owned()),
+				("Signed-off-by".to_owned(), "A <a@b.c>".to_owned()),
+			]
+		);
+		assert!(commit("Fix: lint").trailers().is_empty());
+		assert!(commit("Fix lint\n\nSee https://example.com\nfor details").trailers().is_empty());
+	}
+
+	#[test]
+	fn test_version_sha() {
+		let mr = |state: &str, merge_commit_sha: Option<&str>| -> MergeRequest {
+			serde_json::from_value(serde_json::json!({
+				"iid": 1,
+				"title": "title",
+				"state": state,
+				"labels": [],
+				"sha": "head",
+				"author": { "id": 1, "name": "A", "username": "a" },
+				"updated_at": "2025-01-01T00:00:00Z",
+				"project_id": 1,
+				"source_project_id": 2,
+				"source_branch": "feature",
+				"target_branch": "main",
+				"web_url": "https://gitlab.com/a/b/-/merge_requests/1",
+				"merge_commit_sha": merge_commit_sha,
+			}))
+			.unwrap()
+		};
+		assert_eq!(mr("opened", None).version_sha().unwrap(), "head");
+		assert_eq!(mr("opened", None).version_project_id(), 2);
+		assert_eq!(mr("merged", Some("merge")).version_sha().unwrap(), "merge");
+		assert_eq!(mr("merged", Some("merge")).version_project_id(), 1);
+		/* fast-forward merge */
+		assert_eq!(mr("merged", None).version_sha().unwrap(), "head");
+
+		/* a head built before the merge stays in the source project, on the source branch */
+		assert!(mr("merged", Some("merge")).is_merged_version("merge"));
+		assert!(!mr("merged", Some("merge")).is_merged_version("head"));
+		assert_eq!(mr("merged", Some("merge")).project_id_of("head"), 2);
+		assert_eq!(mr("merged", Some("merge")).project_id_of("merge"), 1);
+		assert!(!mr("opened", None).is_merged_version("head"));
+		assert_eq!(mr("opened", None).project_id_of("head"), 2);
+	}
+
+	#[test]
+	fn test_version_keys() {
+		let version: Version =
+			serde_json::from_str(r#"{"iid": "3", "committed_date": "2025-01-01T00:00:00Z", "sha": "abc"}"#).unwrap();
+		/* versions of a single project keep their keys and serialization */
+		assert_eq!(version.mr_key(), "3");
+		assert_eq!(version.commit_key(), "abc");
+		assert!(!serde_json::to_string(&version).unwrap().contains("project"));
+
+		let version = Version {
+			project: Some("42".to_owned()),
+			..version
+		};
+		assert_eq!(version.mr_key(), "42!3");
+		assert_eq!(version.commit_key(), "42@abc");
+	}
 }
@@ -0,0 +1,178 @@
+//! Track review apps as GitLab deployments and environments.
+use crate::common::{
+	Deployment,
+	Environment,
+	MergeRequest,
+	Version,
+};
+use crate::endpoints::{
+	EditEnvironment,
+	StopEnvironment,
+};
+use anyhow::{
+	anyhow,
+	Result,
+};
+use gitlab::api::{
+	self,
+	projects::{
+		deployments::{
+			self,
+			DeploymentStatus,
+			DeploymentStatusFilter,
+		},
+		environments,
+	},
+	Query,
+};
+use gitlab::Gitlab;
+
+pub fn parse_deployment_status(status: &str) -> Result<DeploymentStatus> {
+	match status {
+		"running" => Ok(DeploymentStatus::Running),
+		"success" => Ok(DeploymentStatus::Success),
+		"failed" => Ok(DeploymentStatus::Failed),
+		"canceled" => Ok(DeploymentStatus::Canceled),
+		_ => Err(anyhow!("invalid deployment status `{}`", status)),
+	}
+}
+
+/// The ref of the deployed `version`, in the target project where deployments are recorded.
+///
+/// The merged version of an MR (or a closure) is on its target branch. The source branch of a fork does not exist in
+/// the target project, whose `refs/merge-requests/<iid>/head` points at the fork's head.
+pub fn deployment_ref(mr: &MergeRequest, version: &Version) -> Result<String> {
+	if mr.is_merged_version(&version.sha) || version.kind.is_some() {
+		return Ok(mr.target_branch.clone());
+	}
+	if mr.is_fork() {
+		return Ok(format!("refs/merge-requests/{}/head", mr.iid));
+	}
+	mr.source_branch
+		.clone()
+		.ok_or_else(|| anyhow!("MR {} has null source_branch - branch likely deleted", mr.iid))
+}
+
+fn find_environment(client: &Gitlab, project: &str, name: &str) -> Result<Option<Environment>> {
+	let environments: Vec<Environment> =
+		environments::Environments::builder().project(project).name(name).build()?.query(client)?;
+	Ok(environments.into_iter().find(|environment| environment.name == name))
+}
+
+/// Record a deployment of `sha` to `environment`.
+///
+/// A running deployment of the same commit is updated rather than duplicated, so a `running` put followed by a
+/// `success` put shows up as a single deployment.
+pub fn deploy(
+	client: &Gitlab,
+	project: &str,
+	environment: &str,
+	external_url: Option<&str>,
+	sha: &str,
+	ref_name: &str,
+	status: DeploymentStatus,
+) -> Result<Deployment> {
+	let running: Vec<Deployment> = deployments::Deployments::builder()
+		.project(project)
+		.environment(environment)
+		.status(DeploymentStatusFilter::Running)
+		.build()?
+		.query(client)?;
+
+	let deployment: Deployment = if let Some(deployment) = running.into_iter().find(|deployment| deployment.sha == sha) {
+		eprintln!("Updating deployment {} to `{}`", deployment.id, environment);
+		deployments::EditDeployment::builder()
+			.project(project)
+			.deployment_id(deployment.id)
+			.status(status)
+			.build()?
+			.query(client)?
+	} else {
+		eprintln!("Creating deployment of {} to `{}`", sha, environment);
+		deployments::CreateDeployment::builder()
+			.project(project)
+			.environment(environment)
+			.sha(sha)
+			.ref_(ref_name)
+			.tag(false)
+			.status(status)
+			.build()?
+			.query(client)?
+	};
+
+	if let Some(external_url) = external_url {
+		let environment = find_environment(client, project, environment)?
+			.ok_or_else(|| anyhow!("environment `{}` was not created by the deployment", environment))?;
+		if environment.external_url.as_deref() != Some(external_url) {
+			api::ignore(EditEnvironment {
+				project: project.into(),
+				environment: environment.id,
+				external_url: external_url.into(),
+			})
+			.query(client)?;
+		}
+	}
+
+	Ok(deployment)
+}
+
+/// Stop `environment`, returning whether there was an environment to stop.
+pub fn stop(client: &Gitlab, project: &str, environment: &str) -> Result<bool> {
+	match find_environment(client, project, environment)? {
+		Some(found) if found.state != "stopped" => {
+			eprintln!("Stopping environment `{}`", environment);
+			api::ignore(StopEnvironment {
+				project: project.into(),
+				environment: found.id,
+			})
+			.query(client)?;
+			Ok(true)
+		},
+		Some(_) => {
+			eprintln!("Environment `{}` is already stopped", environment);
+			Ok(false)
+		},
+		None => {
+			eprintln!("Environment `{}` does not exist", environment);
+			Ok(false)
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn merge_request(state: &str, source_project_id: u64) -> MergeRequest {
+		serde_json::from_value(serde_json::json!({
+			"iid": 7,
+			"title": "title",
+			"state": state,
+			"labels": [],
+			"sha": "head",
+			"author": { "id": 1, "name": "A", "username": "a" },
+			"updated_at": "2025-01-01T00:00:00Z",
+			"project_id": 1,
+			"source_project_id": source_project_id,
+			"source_branch": "feature",
+			"target_branch": "main",
+			"web_url": "https://gitlab.com/a/b/-/merge_requests/7",
+			"merge_commit_sha": if state == "merged" { Some("merge") } else { None },
+		}))
+		.unwrap()
+	}
+
+	fn version(sha: &str) -> Version {
+		serde_json::from_value(serde_json::json!({ "iid": "7", "committed_date": "2025-01-01T00:00:00Z", "sha": sha }))
+			.unwrap()
+	}
+
+	#[test]
+	fn test_deployment_ref() {
+		assert_eq!(deployment_ref(&merge_request("opened", 1), &version("head")).unwrap(), "feature");
+		assert_eq!(deployment_ref(&merge_request("opened", 2), &version("head")).unwrap(), "refs/merge-requests/7/head");
+		assert_eq!(deployment_ref(&merge_request("merged", 2), &version("merge")).unwrap(), "main");
+		/* a head built before the merge */
+		assert_eq!(deployment_ref(&merge_request("merged", 1), &version("head")).unwrap(), "feature");
+	}
+}
@@ -0,0 +1,130 @@
+//! GitLab API endpoints which are not (yet) provided by the `gitlab` crate.
+use gitlab::api::common::NameOrId;
+use gitlab::api::endpoint_prelude::*;
+use gitlab::api::projects::merge_requests::MergeRequests;
+
+/// Resolve or unresolve a merge request discussion.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct ResolveMergeRequestDiscussion<'a> {
+	pub project: NameOrId<'a>,
+	pub merge_request: u64,
+	pub discussion: Cow<'a, str>,
+	pub resolved: bool,
+}
+
+impl Endpoint for ResolveMergeRequestDiscussion<'_> {
+	fn method(&self) -> Method {
+		Method::PUT
+	}
+
+	fn endpoint(&self) -> Cow<'static, str> {
+		format!(
+			"projects/{}/merge_requests/{}/discussions/{}",
+			self.project, self.merge_request, self.discussion,
+		)
+		.into()
+	}
+
+	fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+		let mut params = FormParams::default();
+
+		params.push("resolved", self.resolved);
+		params.into_body()
+	}
+}
+
+/// Edit the external URL of an environment.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct EditEnvironment<'a> {
+	pub project: NameOrId<'a>,
+	pub environment: u64,
+	pub external_url: Cow<'a, str>,
+}
+
+impl Endpoint for EditEnvironment<'_> {
+	fn method(&self) -> Method {
+		Method::PUT
+	}
+
+	fn endpoint(&self) -> Cow<'static, str> {
+		format!("projects/{}/environments/{}", self.project, self.environment).into()
+	}
+
+	fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+		let mut params = FormParams::default();
+
+		params.push("external_url", self.external_url.as_ref());
+		params.into_body()
+	}
+}
+
+/// Stop an environment.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct StopEnvironment<'a> {
+	pub project: NameOrId<'a>,
+	pub environment: u64,
+}
+
+impl Endpoint for StopEnvironment<'_> {
+	fn method(&self) -> Method {
+		Method::POST
+	}
+
+	fn endpoint(&self) -> Cow<'static, str> {
+		format!("projects/{}/environments/{}/stop", self.project, self.environment).into()
+	}
+}
+
+/// Members of a group, including inherited members.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct AllGroupMembers<'a> {
+	pub group: NameOrId<'a>,
+	pub user_ids: Vec<u64>,
+}
+
+impl Endpoint for AllGroupMembers<'_> {
+	fn method(&self) -> Method {
+		Method::GET
+	}
+
+	fn endpoint(&self) -> Cow<'static, str> {
+		format!("groups/{}/members/all", self.group).into()
+	}
+
+	fn parameters(&self) -> QueryParams<'_> {
+		let mut params = QueryParams::default();
+
+		params.extend(self.user_ids.iter().map(|&id| ("user_ids[]", id)));
+		params
+	}
+}
+
+/// Merge requests of a group and its subgroups.
+///
+/// Takes the filters of a project merge request query, its project is ignored.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct GroupMergeRequests<'a> {
+	pub group: NameOrId<'a>,
+	pub filters: MergeRequests<'a>,
+}
+
+impl Endpoint for GroupMergeRequests<'_> {
+	fn method(&self) -> Method {
+		Method::GET
+	}
+
+	fn endpoint(&self) -> Cow<'static, str> {
+		format!("groups/{}/merge_requests", self.group).into()
+	}
+
+	fn parameters(&self) -> QueryParams<'_> {
+		self.filters.parameters()
+	}
+}
+
+impl Pageable for GroupMergeRequests<'_> {}
@@ -0,0 +1,609 @@
+//! Client-side merge request filters applied by `check`.
+//!
+//! Each filter returns the reason a merge request is excluded, or `None` when it passes, so that `check` can log why
+//! every merge request was skipped.
+use crate::common::{
+	Approvals,
+	Author,
+	Commit,
+	ForkPolicy,
+	Member,
+	MergeRequest,
+	Source,
+};
+use crate::endpoints::AllGroupMembers;
+use anyhow::{
+	anyhow,
+	Context,
+	Result,
+};
+use gitlab::api::{
+	self,
+	common::{
+		AccessLevel,
+		NameOrId,
+	},
+	projects::members::AllProjectMembers,
+	Query,
+};
+use glob::Pattern;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Client-side filters of a source, with its patterns compiled once.
+pub struct Filters<'a> {
+	source: &'a Source,
+	target_branches: BranchFilter,
+	source_branches: BranchFilter,
+	trusted_access_level: u64,
+	/* whether each fork MR author seen so far is trusted, per project */
+	trusted_authors: HashMap<(String, u64), bool>,
+}
+
+/// The access level of a user on a project, including inherited membership (0 for non-members).
+pub fn project_access_level<C: api::Client>(client: &C, project: &str, user: u64) -> Result<u64> {
+	let members: Vec<Member> = AllProjectMembers::builder().project(project).user_id(user).build()?.query(client)?;
+	Ok(members.iter().filter(|member| member.id == user).map(|member| member.access_level).max().unwrap_or(0))
+}
+
+/// Parse an access level name (`guest`, `planner`, `reporter`, `developer`, `maintainer` or `owner`).
+pub fn parse_access_level(level: &str) -> Result<AccessLevel> {
+	match level {
+		"guest" => Ok(AccessLevel::Guest),
+		"planner" => Ok(AccessLevel::Planner),
+		"reporter" => Ok(AccessLevel::Reporter),
+		"developer" => Ok(AccessLevel::Developer),
+		"maintainer" => Ok(AccessLevel::Maintainer),
+		"owner" => Ok(AccessLevel::Owner),
+		_ => Err(anyhow!("invalid access level `{}`", level)),
+	}
+}
+
+impl<'a> Filters<'a> {
+	pub fn new(source: &'a Source) -> Result<Self> {
+		Ok(Self {
+			source,
+			target_branches: BranchFilter::new(source.target_branches.iter().flatten())
+				.context("invalid `target_branches`")?,
+			source_branches: BranchFilter::new(source.source_branches.iter().flatten())
+				.context("invalid `source_branches`")?,
+			trusted_access_level: parse_access_level(source.trusted_access_level.as_deref().unwrap_or("developer"))?
+				.as_u64(),
+			trusted_authors: HashMap::new(),
+		})
+	}
+
+	/// Why `mr` is excluded by the `fork_policy`, if it is.
+	///
+	/// With the `trusted` policy, the author of a fork MR must be listed in `trusted_users`, be a member of one of the
+	/// `trusted_groups`, or have at least `trusted_access_level` on `project`.
+	pub fn fork_reason<C: api::Client>(&mut self, client: &C, project: &str, mr: &MergeRequest) -> Result<Option<String>> {
+		if !mr.is_fork() {
+			return Ok(None);
+		}
+		match self.source.fork_policy.unwrap_or_default() {
+			ForkPolicy::Allow => Ok(None),
+			ForkPolicy::Skip => Ok(Some("MR is from a fork".to_owned())),
+			ForkPolicy::Trusted => {
+				let key = (project.to_owned(), mr.author.id);
+				let trusted = match self.trusted_authors.get(&key) {
+					Some(&trusted) => trusted,
+					None => {
+						let trusted = self.is_trusted(client, project, &mr.author)?;
+						self.trusted_authors.insert(key, trusted);
+						trusted
+					},
+				};
+				Ok((!trusted).then(|| format!("MR is from a fork and `{}` is not trusted", mr.author.username)))
+			},
+		}
+	}
+
+	fn is_trusted<C: api::Client>(&self, client: &C, project: &str, author: &Author) -> Result<bool> {
+		if self.source.trusted_users.iter().flatten().any(|entry| is_user(author, entry)) {
+			return Ok(true);
+		}
+		for group in self.source.trusted_groups.iter().flatten() {
+			let group = match group.parse::<u64>() {
+				Ok(id) => NameOrId::from(id),
+				Err(_) => NameOrId::from(group.as_str()),
+			};
+			let members: Vec<Member> = AllGroupMembers {
+				group,
+				user_ids: vec![author.id],
+			}
+			.query(client)?;
+			if members.iter().any(|member| member.id == author.id) {
+				return Ok(true);
+			}
+		}
+		Ok(project_access_level(client, project, author.id)? >= self.trusted_access_level)
+	}
+
+	/// The target branch GitLab can filter on, when `target_branches` is a single literal branch.
+	pub fn target_branch(&self) -> Option<&str> {
+		self.target_branches.literal()
+	}
+
+	/// The source branch GitLab can filter on, when `source_branches` is a single literal branch.
+	pub fn source_branch(&self) -> Option<&str> {
+		self.source_branches.literal()
+	}
+
+	/// Why `mr` is excluded, if it is.
+	pub fn exclusion_reason(&self, mr: &MergeRequest) -> Option<String> {
+		self.target_branches
+			.reason("target branch", Some(&mr.target_branch))
+			.or_else(|| self.source_branches.reason("source branch", mr.source_branch.as_deref()))
+			.or_else(|| labels_reason(self.source, mr))
+			.or_else(|| people_reason(self.source, mr))
+			.or_else(|| merge_status_reason(self.source, mr))
+	}
+}
+
+#[derive(Debug)]
+enum BranchMatcher {
+	Literal(String),
+	Glob(Pattern),
+	Regex(Regex),
+}
+
+impl BranchMatcher {
+	fn matches(&self, branch: &str) -> bool {
+		match self {
+			Self::Literal(literal) => literal == branch,
+			Self::Glob(pattern) => pattern.matches(branch),
+			Self::Regex(regex) => regex.is_match(branch),
+		}
+	}
+}
+
+/// Branch patterns: globs (`release/*`), regexes between slashes (`/^v\d+$/`), negated with a leading `!`.
+///
+/// A branch matches when it matches any of the positive patterns (or there is none) and none of the negated ones.
+#[derive(Debug, Default)]
+struct BranchFilter {
+	patterns: Vec<(String, bool, BranchMatcher)>,
+}
+
+impl BranchFilter {
+	fn new<'b>(patterns: impl IntoIterator<Item = &'b String>) -> Result<Self> {
+		let patterns = patterns
+			.into_iter()
+			.map(|pattern| {
+				let (negate, body) = match pattern.strip_prefix('!') {
+					Some(body) => (true, body),
+					None => (false, pattern.as_str()),
+				};
+				let matcher = if let Some(regex) = body.strip_prefix('/').and_then(|body| body.strip_suffix('/')) {
+					BranchMatcher::Regex(Regex::new(regex).with_context(|| anyhow!("invalid regex `{}`", pattern))?)
+				} else if body.contains(['*', '?', '[']) {
+					BranchMatcher::Glob(Pattern::new(body).with_context(|| anyhow!("invalid glob `{}`", pattern))?)
+				} else {
+					BranchMatcher::Literal(body.to_owned())
+				};
+				Ok((pattern.clone(), negate, matcher))
+			})
+			.collect::<Result<_>>()?;
+		Ok(Self { patterns })
+	}
+
+	fn literal(&self) -> Option<&str> {
+		match self.patterns.as_slice() {
+			[(_, false, BranchMatcher::Literal(literal))] => Some(literal),
+			_ => None,
+		}
+	}
+
+	fn reason(&self, kind: &str, branch: Option<&str>) -> Option<String> {
+		if self.patterns.is_empty() {
+			return None;
+		}
+		let Some(branch) = branch else {
+			return self
+				.patterns
+				.iter()
+				.any(|(_, negate, _)| !negate)
+				.then(|| format!("{} is deleted", kind));
+		};
+		if let Some((pattern, ..)) = self.patterns.iter().find(|(_, negate, matcher)| *negate && matcher.matches(branch)) {
+			return Some(format!("{} `{}` is excluded by `{}`", kind, branch, pattern));
+		}
+		let mut positive = self.patterns.iter().filter(|(_, negate, _)| !negate).peekable();
+		if positive.peek().is_some() && !positive.any(|(_, _, matcher)| matcher.matches(branch)) {
+			return Some(format!("{} `{}` matches none of the patterns", kind, branch));
+		}
+		None
+	}
+}
+
+/// Why the head commit of an MR is skipped, if its message contains a skip marker.
+pub fn skip_ci_reason(source: &Source, commit: &Commit) -> Option<String> {
+	let message = commit.message.to_lowercase();
+	match &source.skip_ci_markers {
+		Some(markers) => markers.iter().find(|marker| message.contains(&marker.to_lowercase())).map(String::as_str),
+		None => ["[ci skip]", "[skip ci]"].into_iter().find(|marker| message.contains(marker)),
+	}
+	.map(|marker| format!("head commit is marked `{}`", marker))
+}
+
+/// Filter on `skip_conflicted` and `merge_status_allow`, for open MRs: merged or closed ones cannot be merged anymore.
+pub fn merge_status_reason(source: &Source, mr: &MergeRequest) -> Option<String> {
+	if mr.state != "opened" {
+		return None;
+	}
+	let status = mr.detailed_merge_status.as_deref();
+	if source.skip_conflicted.unwrap_or(false) && (mr.has_conflicts || status == Some("conflict")) {
+		return Some("has merge conflicts".to_owned());
+	}
+	match &source.merge_status_allow {
+		Some(allow) if !status.is_some_and(|status| allow.iter().any(|allowed| allowed == status)) => {
+			Some(format!("merge status `{}` is not in {:?}", status.unwrap_or("unknown"), allow))
+		},
+		_ => None,
+	}
+}
+
+/// Whether the approvals of MRs are needed by `approval_reason`.
+pub fn requires_approvals(source: &Source) -> bool {
+	source.require_approved.unwrap_or(false) || source.min_approvals.is_some_and(|min| min > 0)
+}
+
+/// Why an MR is excluded by `require_approved` or `min_approvals`, if it is.
+pub fn approval_reason(source: &Source, approvals: &Approvals) -> Option<String> {
+	if source.require_approved.unwrap_or(false) && !approvals.approved {
+		return Some("not approved yet".to_owned());
+	}
+	match source.min_approvals {
+		Some(min) if approvals.approved_by.len() < min => {
+			Some(format!("approved by {} of {} required users", approvals.approved_by.len(), min))
+		},
+		_ => None,
+	}
+}
+
+/// Whether a label pattern contains wildcards, such as `team::*`, and so cannot be passed to GitLab.
+///
+/// Only `*` and `?` are wildcards: brackets are common in labels, e.g. `[WIP]`.
+pub fn is_label_pattern(pattern: &str) -> bool {
+	pattern.contains(['*', '?'])
+}
+
+fn label_matches(pattern: &str, label: &str) -> bool {
+	if is_label_pattern(pattern) {
+		/* brackets are literal, as `[[]` and `[]]` in a glob */
+		let pattern = pattern.replace('[', "\u{0}").replace(']', "[]]").replace('\u{0}', "[[]");
+		Pattern::new(&pattern).is_ok_and(|pattern| pattern.matches(label))
+	} else {
+		pattern == label
+	}
+}
+
+fn has_label(mr: &MergeRequest, pattern: &str) -> bool {
+	mr.labels.iter().any(|label| label_matches(pattern, label))
+}
+
+/// Filter on `labels` (all), `any_labels` (at least one) and `ignore_labels` (none).
+pub fn labels_reason(source: &Source, mr: &MergeRequest) -> Option<String> {
+	if let Some(ignored) = source.ignore_labels.iter().flatten().find(|pattern| has_label(mr, pattern)) {
+		return Some(format!("has ignored label `{}`", ignored));
+	}
+	if let Some(missing) = source.labels.iter().flatten().find(|pattern| !has_label(mr, pattern)) {
+		return Some(format!("missing label `{}`", missing));
+	}
+	match &source.any_labels {
+		Some(any) if !any.is_empty() && !any.iter().any(|pattern| has_label(mr, pattern)) => {
+			Some(format!("none of the labels {:?}", any))
+		},
+		_ => None,
+	}
+}
+
+/// Whether a configured user (a username, or a numeric user ID) designates `user`.
+fn is_user(user: &Author, entry: &str) -> bool {
+	match entry.parse::<u64>() {
+		Ok(id) => user.id == id,
+		Err(_) => user.username.eq_ignore_ascii_case(entry.trim_start_matches('@')),
+	}
+}
+
+/// Apply an allow list and a deny list to the users holding a role (author, assignees or reviewers).
+fn users_reason(role: &str, users: &[&Author], allow: Option<&Vec<String>>, deny: Option<&Vec<String>>) -> Option<String> {
+	if let Some(deny) = deny {
+		if let Some(user) = users.iter().find(|user| deny.iter().any(|entry| is_user(user, entry))) {
+			return Some(format!("{} `{}` is ignored", role, user.username));
+		}
+	}
+	match allow {
+		Some(allow) if !allow.is_empty() && !users.iter().any(|user| allow.iter().any(|entry| is_user(user, entry))) => {
+			let users = users.iter().map(|user| user.username.as_str()).collect::<Vec<_>>();
+			Some(format!("no {} among {:?} (found {:?})", role, allow, users))
+		},
+		_ => None,
+	}
+}
+
+/// Filter on author, assignees, reviewers and milestone.
+pub fn people_reason(source: &Source, mr: &MergeRequest) -> Option<String> {
+	users_reason("author", &[&mr.author], source.authors.as_ref(), source.ignore_authors.as_ref())
+		.or_else(|| {
+			let assignees = mr.assignees.iter().collect::<Vec<_>>();
+			users_reason("assignee", &assignees, source.assignees.as_ref(), source.ignore_assignees.as_ref())
+		})
+		.or_else(|| {
+			let reviewers = mr.reviewers.iter().collect::<Vec<_>>();
+			users_reason("reviewer", &reviewers, source.reviewers.as_ref(), source.ignore_reviewers.as_ref())
+		})
+		.or_else(|| {
+			let milestone = source.milestone.as_deref()?;
+			let title = mr.milestone.as_ref().map(|milestone| milestone.title.as_str());
+			let matches = match milestone {
+				"None" => title.is_none(),
+				"Any" => title.is_some(),
+				_ => title == Some(milestone),
+			};
+			(!matches).then(|| format!("milestone {:?} is not `{}`", title, milestone))
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::Approver;
+
+	fn user(id: u64, username: &str) -> Author {
+		Author {
+			id,
+			name: username.to_owned(),
+			username: username.to_owned(),
+		}
+	}
+
+	fn merge_request(value: serde_json::Value) -> MergeRequest {
+		let mut mr = serde_json::json!({
+			"iid": 1,
+			"title": "title",
+			"state": "opened",
+			"labels": [],
+			"sha": "abc",
+			"author": { "id": 1, "name": "Renovate", "username": "renovate-bot" },
+			"updated_at": "2025-01-01T00:00:00Z",
+			"project_id": 1,
+			"source_project_id": 1,
+			"source_branch": "feature",
+			"target_branch": "main",
+			"web_url": "https://gitlab.com/a/b/-/merge_requests/1",
+		});
+		for (key, value) in value.as_object().unwrap() {
+			mr[key] = value.clone();
+		}
+		serde_json::from_value(mr).unwrap()
+	}
+
+	#[test]
+	fn test_labels() {
+		let mr = merge_request(serde_json::json!({ "labels": ["team::backend", "priority::high"] }));
+		let source = Source {
+			labels: list(&["team::*"]),
+			any_labels: list(&["bug", "priority::high"]),
+			..Default::default()
+		};
+		assert_eq!(Filters::new(&source).unwrap().exclusion_reason(&mr), None);
+
+		let source = Source {
+			ignore_labels: list(&["ci::skip", "priority::*"]),
+			..Default::default()
+		};
+		assert!(labels_reason(&source, &mr).unwrap().contains("priority::*"));
+
+		let source = Source {
+			labels: list(&["team::backend", "team"]),
+			..Default::default()
+		};
+		assert!(labels_reason(&source, &mr).is_some());
+
+		let source = Source {
+			any_labels: list(&["bug", "do-not-build"]),
+			..Default::default()
+		};
+		assert!(labels_reason(&source, &mr).is_some());
+		let mr = merge_request(serde_json::json!({ "labels": ["[WIP]", "[bug] crash"] }));
+		let source = Source {
+			labels: list(&["[WIP]", "[bug] *"]),
+			..Default::default()
+		};
+		assert!(!is_label_pattern("[WIP]"));
+		assert_eq!(labels_reason(&source, &mr), None);
+	}
+
+	#[test]
+	fn test_branches() {
+		let mr = merge_request(serde_json::json!({ "source_branch": "renovate/serde-1.x", "target_branch": "release/1.2" }));
+		let source = Source {
+			target_branches: list(&["main", "release/*"]),
+			source_branches: list(&["!renovate/*"]),
+			..Default::default()
+		};
+		let filters = Filters::new(&source).unwrap();
+		assert!(filters.exclusion_reason(&mr).unwrap().contains("renovate/*"));
+		assert_eq!(filters.target_branch(), None);
+
+		let source = Source {
+			target_branches: list(&[r"/^release/\d+\.\d+$/"]),
+			source_branches: list(&["renovate/serde-1.x"]),
+			..Default::default()
+		};
+		let filters = Filters::new(&source).unwrap();
+		assert_eq!(filters.exclusion_reason(&mr), None);
+		assert_eq!(filters.source_branch(), Some("renovate/serde-1.x"));
+
+		let deleted = merge_request(serde_json::json!({ "source_branch": null }));
+		assert!(filters.exclusion_reason(&deleted).is_some());
+
+		let source = Source {
+			target_branches: list(&["/(unclosed/"]),
+			..Default::default()
+		};
+		assert!(Filters::new(&source).is_err());
+	}
+
+	#[test]
+	fn test_parse_access_level() {
+		assert_eq!(parse_access_level("developer").unwrap().as_u64(), 30);
+		assert_eq!(parse_access_level("maintainer").unwrap().as_u64(), 40);
+		assert!(parse_access_level("admin").is_err());
+	}
+
+	#[test]
+	fn test_skip_ci_reason() {
+		let commit = |message: &str| Commit {
+			id: "abc".to_owned(),
+			committed_date: "2025-01-01T00:00:00Z".to_owned(),
+			message: message.to_owned(),
+		};
+		let source = Source::default();
+		assert!(skip_ci_reason(&source, &commit("Update docs [CI SKIP]")).is_some());
+		assert!(skip_ci_reason(&source, &commit("Update docs\n\n[skip ci]")).is_some());
+		assert_eq!(skip_ci_reason(&source, &commit("Update docs")), None);
+
+		let source = Source {
+			skip_ci_markers: list(&["[wip]"]),
+			..Default::default()
+		};
+		assert!(skip_ci_reason(&source, &commit("[WIP] parser")).is_some());
+		assert_eq!(skip_ci_reason(&source, &commit("Update docs [ci skip]")), None);
+	}
+
+	#[test]
+	fn test_approval_reason() {
+		let approvals = |approved: bool, users: usize| Approvals {
+			approved,
+			approved_by: (0..users as u64).map(|id| Approver { user: user(id, "user") }).collect(),
+		};
+		let source = Source {
+			require_approved: Some(true),
+			..Default::default()
+		};
+		assert!(requires_approvals(&source));
+		assert!(approval_reason(&source, &approvals(false, 1)).is_some());
+		assert_eq!(approval_reason(&source, &approvals(true, 0)), None);
+
+		let source = Source {
+			min_approvals: Some(2),
+			..Default::default()
+		};
+		assert!(approval_reason(&source, &approvals(true, 1)).is_some());
+		assert_eq!(approval_reason(&source, &approvals(false, 2)), None);
+		assert!(!requires_approvals(&Source::default()));
+	}
+
+	#[test]
+	fn test_merge_status_reason() {
+		let conflicted = merge_request(serde_json::json!({ "has_conflicts": true, "detailed_merge_status": "conflict" }));
+		let rebase = merge_request(serde_json::json!({ "detailed_merge_status": "need_rebase" }));
+		let mergeable = merge_request(serde_json::json!({ "detailed_merge_status": "mergeable" }));
+		let source = Source {
+			skip_conflicted: Some(true),
+			..Default::default()
+		};
+		assert!(merge_status_reason(&source, &conflicted).is_some());
+		assert_eq!(merge_status_reason(&source, &rebase), None);
+
+		let source = Source {
+			merge_status_allow: list(&["mergeable", "ci_must_pass"]),
+			..Default::default()
+		};
+		assert!(merge_status_reason(&source, &rebase).is_some());
+		assert_eq!(merge_status_reason(&source, &mergeable), None);
+		assert!(merge_status_reason(&source, &merge_request(serde_json::json!({}))).is_some());
+
+		let merged = merge_request(serde_json::json!({ "state": "merged", "detailed_merge_status": "not_open" }));
+		assert_eq!(merge_status_reason(&source, &merged), None);
+		let closed = merge_request(serde_json::json!({ "state": "closed", "has_conflicts": true }));
+		assert_eq!(merge_status_reason(&source, &closed), None);
+	}
+
+	#[test]
+	fn test_is_fork() {
+		assert!(!merge_request(serde_json::json!({})).is_fork());
+		assert!(merge_request(serde_json::json!({ "source_project_id": 2 })).is_fork());
+	}
+
+	fn list(entries: &[&str]) -> Option<Vec<String>> {
+		Some(entries.iter().map(|entry| entry.to_string()).collect())
+	}
+
+	#[test]
+	fn test_is_user() {
+		let renovate = user(42, "renovate-bot");
+		assert!(is_user(&renovate, "42"));
+		assert!(is_user(&renovate, "Renovate-Bot"));
+		assert!(is_user(&renovate, "@renovate-bot"));
+		assert!(!is_user(&renovate, "43"));
+		assert!(!is_user(&renovate, "dependabot"));
+	}
+
+	#[test]
+	fn test_authors() {
+		let mr = merge_request(serde_json::json!({}));
+		let source = Source {
+			authors: list(&["renovate-bot"]),
+			..Default::default()
+		};
+		assert_eq!(people_reason(&source, &mr), None);
+
+		let source = Source {
+			ignore_authors: list(&["1"]),
+			..Default::default()
+		};
+		assert!(people_reason(&source, &mr).is_some());
+
+		let source = Source {
+			authors: list(&["alice", "bob"]),
+			..Default::default()
+		};
+		assert!(people_reason(&source, &mr).is_some());
+	}
+
+	#[test]
+	fn test_assignees_and_reviewers() {
+		let mr = merge_request(serde_json::json!({
+			"assignees": [{ "id": 2, "name": "Alice", "username": "alice" }],
+			"reviewers": [{ "id": 3, "name": "Bob", "username": "bob" }, { "id": 4, "name": "Eve", "username": "eve" }],
+		}));
+		let source = Source {
+			assignees: list(&["alice"]),
+			reviewers: list(&["4"]),
+			..Default::default()
+		};
+		assert_eq!(people_reason(&source, &mr), None);
+
+		let source = Source {
+			ignore_reviewers: list(&["eve"]),
+			..Default::default()
+		};
+		assert!(people_reason(&source, &mr).is_some());
+
+		let unassigned = merge_request(serde_json::json!({}));
+		let source = Source {
+			assignees: list(&["alice"]),
+			..Default::default()
+		};
+		assert!(people_reason(&source, &unassigned).is_some());
+	}
+
+	#[test]
+	fn test_milestone() {
+		let mr = merge_request(serde_json::json!({ "milestone": { "title": "v1.0" } }));
+		let none = merge_request(serde_json::json!({ "milestone": null }));
+		let source = |milestone: &str| Source {
+			milestone: Some(milestone.to_owned()),
+			..Default::default()
+		};
+		assert_eq!(people_reason(&source("v1.0"), &mr), None);
+		assert!(people_reason(&source("v2.0"), &mr).is_some());
+		assert_eq!(people_reason(&source("Any"), &mr), None);
+		assert!(people_reason(&source("Any"), &none).is_some());
+		assert_eq!(people_reason(&source("None"), &none), None);
+	}
+}
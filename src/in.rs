@@ -17,6 +17,7 @@ use gitlab::api::{
 	projects::{
 		self,
 		merge_requests,
+		repository::commits,
 	},
 	Query,
 };
@@ -68,6 +69,24 @@ struct Args {
 	directory: String,
 }
 
+/// The commit to check out for `version` of `mr` and the branch to clone for it: the commit of the version, even if
+/// the MR has new commits since.
+///
+/// The merged version of an MR is checked out at its merge commit, on the target branch (a closed one is not cloned).
+/// Decided from the version: a head built before the MR was merged is still on its source branch.
+fn checkout_of(mr: &MergeRequest, version: &Version) -> Result<(String, String)> {
+	if mr.is_merged_version(&version.sha) || version.kind.is_some() {
+		return Ok((version.sha.clone(), mr.target_branch.clone()));
+	}
+	// Check if SHA is null (happens when source branch is deleted)
+	if mr.sha.is_none() {
+		return Err(anyhow!("MR {} has null SHA - source branch likely deleted", version.iid));
+	}
+	let source_branch = mr.source_branch.clone()
+		.ok_or_else(|| anyhow!("MR {} has null source_branch - branch likely deleted", version.iid))?;
+	Ok((version.sha.clone(), source_branch))
+}
+
 fn main() -> Result<()> {
 	let args = Args::parse();
 
@@ -79,25 +98,29 @@ fn main() -> Result<()> {
 
 	let version = input.version.as_ref().unwrap();
 
+	// Versions of a group or several projects know the project of their MR
 	let mr: MergeRequest = merge_requests::MergeRequest::builder()
-		.project(uri.path().trim_start_matches('/').trim_end_matches(".git"))
+		.project(version.project.as_deref().unwrap_or_else(|| uri.path().trim_start_matches('/').trim_end_matches(".git")))
 		.merge_request(version.iid.parse::<u64>()?)
 		.build()?
 		.query(&client)?;
-	
-	// Check if SHA is null (happens when source branch is deleted)
-	let sha = mr.sha.as_ref()
-		.ok_or_else(|| anyhow!("MR {} has null SHA - source branch likely deleted", version.iid))?;
-	
-	let source_branch = mr.source_branch.as_ref()
-		.ok_or_else(|| anyhow!("MR {} has null source_branch - branch likely deleted", version.iid))?;
+
+	let (sha, branch) = checkout_of(&mr, version)?;
 
 	let project: Project = projects::Project::builder()
-		.project(mr.source_project_id)
+		.project(mr.project_id_of(&version.sha))
 		.build()?
 		.query(&client)?;
 
-	let output = ResourceOutput {
+	/* the trailers are those of the version, whichever commit is the head of the MR now */
+	let commit: Commit = commits::Commit::builder()
+		.project(mr.project_id_of(&version.sha))
+		.commit(version.sha.as_str())
+		.build()?
+		.query(&client)?;
+
+	let is_fork = mr.is_fork();
+	let mut output = ResourceOutput {
 		version: version.clone(),
 		metadata: vec![
 			Metadata {
@@ -112,12 +135,51 @@ fn main() -> Result<()> {
 				name: "title".to_owned(),
 				value: mr.title,
 			},
+			Metadata {
+				name: "is_fork".to_owned(),
+				value: is_fork.to_string(),
+			},
+			Metadata {
+				name: "state".to_owned(),
+				value: mr.state.clone(),
+			},
+			Metadata {
+				name: "source_branch".to_owned(),
+				value: mr.source_branch.clone().unwrap_or_default(),
+			},
+			Metadata {
+				name: "target_branch".to_owned(),
+				value: mr.target_branch.clone(),
+			},
 		],
 	};
+	if let Some(closed_at) = mr.merged_at.as_ref().or(mr.closed_at.as_ref()) {
+		output.metadata.push(Metadata {
+			name: "closed_at".to_owned(),
+			value: closed_at.clone(),
+		});
+	}
+	/* commit trailers, e.g. `Concourse-Skip: lint` as `trailer:Concourse-Skip`, repeated keys comma separated */
+	for (key, value) in commit.trailers() {
+		let name = format!("trailer:{}", key);
+		match output.metadata.iter_mut().find(|metadata| metadata.name == name) {
+			Some(metadata) => {
+				metadata.value.push_str(", ");
+				metadata.value.push_str(&value);
+			},
+			None => output.metadata.push(Metadata { name, value }),
+		}
+	}
 
 	println!("{}", serde_json::to_string_pretty(&output)?);
 
-	if !input.is_clone_skippable() {
+	/* the source branch of a closed MR is usually gone, and tearing down needs no checkout of it */
+	let is_closure = version.kind.as_deref() == Some("closed");
+	if is_closure {
+		eprintln!("Not cloning MR {}: it is closed", version.iid);
+	}
+
+	if !input.is_clone_skippable() && !is_closure {
 		eprintln!("Cloning repository...");
 		let mut cb = RemoteCallbacks::new();
 		cb.credentials(|_, _, _| Cred::userpass_plaintext("oauth2", &input.source.private_token));
@@ -128,11 +190,13 @@ fn main() -> Result<()> {
 		let mut builder = RepoBuilder::new();
 		let repo = builder
 			.fetch_options(fo)
-			.branch(source_branch)
+			.branch(&branch)
 			.clone(&project.http_url_to_repo, Path::new(&args.directory))
 			.with_context(|| anyhow!("failed to clone repository"))?;
+		let object = repo.find_object(Oid::from_str(&sha)?, None)
+			.with_context(|| anyhow!("commit {} not found in the clone of {}", sha, branch))?;
 		repo.reset(
-			&repo.find_object(Oid::from_str(sha).unwrap(), None).unwrap(),
+			&object,
 			git2::ResetType::Hard,
 			None,
 		)
@@ -149,12 +213,79 @@ fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
 	use super::{
+		checkout_of,
+		MergeRequest,
 		Params,
 		ResourceInput,
 		Source,
+		Version,
 	};
 	use rstest::rstest;
 
+	fn merge_request(value: serde_json::Value) -> MergeRequest {
+		let mut mr = serde_json::json!({
+			"iid": 1,
+			"title": "title",
+			"state": "opened",
+			"labels": [],
+			"sha": "head",
+			"author": { "id": 1, "name": "Renovate", "username": "renovate-bot" },
+			"updated_at": "2025-01-01T00:00:00Z",
+			"project_id": 1,
+			"source_project_id": 1,
+			"source_branch": "feature",
+			"target_branch": "main",
+			"web_url": "https://gitlab.com/a/b/-/merge_requests/1",
+		});
+		for (key, value) in value.as_object().unwrap() {
+			mr[key] = value.clone();
+		}
+		serde_json::from_value(mr).unwrap()
+	}
+
+	fn version(sha: &str, kind: Option<&str>) -> Version {
+		Version {
+			iid: "1".to_owned(),
+			committed_date: "2025-01-01T00:00:00Z".to_owned(),
+			sha: sha.to_owned(),
+			trigger: None,
+			kind: kind.map(str::to_owned),
+			project: None,
+		}
+	}
+
+	#[rstest]
+	#[case::head(serde_json::json!({}), "head", None, ("head", "feature"))]
+	#[case::older_commit(serde_json::json!({}), "older", None, ("older", "feature"))]
+	#[case::merged(
+		serde_json::json!({ "state": "merged", "merge_commit_sha": "merge" }),
+		"merge",
+		Some("merged"),
+		("merge", "main")
+	)]
+	#[case::head_of_merged(
+		serde_json::json!({ "state": "merged", "merge_commit_sha": "merge" }),
+		"head",
+		None,
+		("head", "feature")
+	)]
+	fn test_checkout_of(
+		#[case] mr: serde_json::Value,
+		#[case] sha: &str,
+		#[case] kind: Option<&str>,
+		#[case] expect: (&str, &str),
+	) {
+		let mr = merge_request(mr);
+		let (sha, branch) = checkout_of(&mr, &version(sha, kind)).unwrap();
+		assert_eq!((sha.as_str(), branch.as_str()), expect);
+	}
+
+	#[test]
+	fn test_checkout_of_deleted_branch() {
+		let mr = merge_request(serde_json::json!({ "sha": null }));
+		assert!(checkout_of(&mr, &version("older", None)).is_err());
+	}
+
 	#[rstest]
 	#[case::no_params(None, false)]
 	#[case::skip_true(Some(Params { skip_clone: Some(true) }), true)]
@@ -165,16 +296,9 @@ mod tests {
 		let input = ResourceInput {
 			params: params,
 			source: Source {
-				labels: None,
-				paths: None,
 				private_token: "".to_owned(),
 				uri: "".to_owned(),
-				skip_draft: None,
-				target_branch: None,
-				max_age_days: None,
-				commit_date_window_days: None,
-				skip_mr_with_ci_status: None,
-				disable_resurrection: None,
+				..Default::default()
 			},
 			version: None,
 		};
@@ -1,4 +1,9 @@
 mod common;
+mod deployments;
+mod endpoints;
+mod reports;
+mod review;
+mod template;
 use anyhow::{
 	anyhow,
 	Context,
@@ -8,8 +13,12 @@ use clap::Parser;
 use common::*;
 use gitlab::{
 	api::{
+		self,
 		projects::{
-			merge_requests,
+			merge_requests::{
+				self,
+				notes,
+			},
 			repository::commits,
 		},
 		Query,
@@ -23,15 +32,136 @@ use serde::{
 use std::env;
 use std::fs::File;
 use std::io;
-use std::path::Path;
+use std::path::{
+	Path,
+	PathBuf,
+};
+use std::thread;
+use std::time::{
+	Duration,
+	Instant,
+};
 use url::Url;
 
+/// Default number of seconds to wait for GitLab to finish a rebase
+const DEFAULT_REBASE_TIMEOUT_SECONDS: u64 = 300;
+const REBASE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+	/// Set a commit status on the merge request head
+	#[default]
+	Status,
+	/// Rebase the merge request onto its target branch
+	Rebase,
+	/// Comment static-analysis findings on the changed lines
+	Review,
+	/// Record a deployment of the merge request to its review environment
+	Deploy,
+	/// Stop the review environment of the merge request
+	Stop,
+}
+
+/// Default name of the review environment of a merge request
+const DEFAULT_ENVIRONMENT: &str = "review/%MR_IID%";
+
+/// Default maximum number of discussions opened by a single review
+const DEFAULT_MAX_COMMENTS: usize = 25;
+
+/// A commit status to create; `statuses` entries and the single status of a put share this shape.
+#[derive(Debug, Deserialize, Clone)]
+struct StatusEntry {
+	name: String,
+	status: String,
+	description: Option<String>,
+	coverage: Option<f64>,
+	target_url: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 struct Params {
-	resource_name: String,
-	status: String,
+	/// Name of the resource as fetched by a `get` earlier in the job; discovered if there is only one
+	resource_name: Option<String>,
+	/// Merge request to update, for jobs without a `get` of the resource
+	iid: Option<u64>,
+	/// Project of `iid` or `sha` when watching a group or several projects (default: the project of `uri`)
+	project: Option<String>,
+	/// Commit to update (default: the head of the merge request)
+	sha: Option<String>,
+	#[serde(default)]
+	action: Action,
+	status: Option<String>,
+	/// File (relative to the build directory) containing the status; falls back to `status` if missing
+	status_file: Option<String>,
 	pipeline_name: Option<String>,
 	coverage: Option<f64>,
+	/// File (relative to the build directory) containing a number, a Cobertura report or an lcov report
+	coverage_file: Option<String>,
+	/// Status description template
+	description: Option<String>,
+	/// File (relative to the build directory) used as the description; falls back to `description` if missing
+	description_file: Option<String>,
+	/// Overrides the link to the Concourse build
+	target_url: Option<String>,
+	/// Ref the status belongs to (default: the MR source branch)
+	#[serde(rename = "ref")]
+	ref_name: Option<String>,
+	pipeline_id: Option<u64>,
+	/// Skip triggering CI for the rebased commit (rebase action only)
+	skip_ci: Option<bool>,
+	/// Seconds to wait for `rebase_in_progress` to clear (rebase action only)
+	rebase_timeout: Option<u64>,
+	/// Several statuses to create at once, instead of `status`
+	statuses: Option<Vec<StatusEntry>>,
+	/// File (relative to the build directory) containing `statuses` as JSON
+	statuses_file: Option<String>,
+	/// Cancel our pending/running statuses on earlier commits of the merge request
+	cancel_superseded: Option<bool>,
+	/// Globs (relative to the build directory) of JUnit XML reports summarized in the description
+	junit_paths: Option<Vec<String>>,
+	/// Also keep a note on the MR up to date with the failing and slowest tests
+	junit_note: Option<bool>,
+	/// SARIF, Checkstyle or Code Climate report (review action only)
+	review_report: Option<String>,
+	/// Format of `review_report`, detected from its contents if not set
+	review_format: Option<reports::ReportFormat>,
+	/// Maximum number of new discussions per run (review action only)
+	max_comments: Option<usize>,
+	/// Name of the reviewed report, whose discussions are kept apart from other reports' (default: the pipeline name)
+	review_name: Option<String>,
+	/// Environment name template (deploy and stop actions)
+	environment: Option<String>,
+	/// External URL template of the environment (deploy action only)
+	environment_url: Option<String>,
+	/// running, success, failed or canceled (deploy action only)
+	deployment_status: Option<String>,
+	/// Labels to remove from the MR once the statuses are set, such as the `rebuild_label` of the source
+	remove_labels: Option<Vec<String>>,
+}
+
+impl Params {
+	/// Reject the params of a single status together with `statuses`, whose entries carry their own.
+	fn check_statuses(&self) -> Result<()> {
+		if self.statuses.is_none() && self.statuses_file.is_none() {
+			return Ok(());
+		}
+		let single = [
+			("status", self.status.is_some()),
+			("status_file", self.status_file.is_some()),
+			("coverage", self.coverage.is_some()),
+			("coverage_file", self.coverage_file.is_some()),
+			("description", self.description.is_some()),
+			("description_file", self.description_file.is_some()),
+			("target_url", self.target_url.is_some()),
+		];
+		let set: Vec<&str> = single.iter().filter(|(_, is_set)| *is_set).map(|(name, _)| *name).collect();
+		if set.is_empty() {
+			Ok(())
+		} else {
+			Err(anyhow!("`{}` cannot be combined with `statuses` or `statuses_file`, set them per entry", set.join("`, `")))
+		}
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,6 +182,20 @@ struct Args {
 	directory: String,
 }
 
+/// Percent-encode a query string component the way Concourse (Go's `url.QueryEscape`) does.
+fn encode_query_component(component: &str) -> String {
+	url::form_urlencoded::byte_serialize(component.as_bytes()).collect()
+}
+
+/// Quote an instance var key which is not a plain identifier, as Concourse does (e.g. `"my.key"`).
+fn quote_instance_var_key(key: &str) -> String {
+	if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+		key.to_owned()
+	} else {
+		serde_json::Value::String(key.to_owned()).to_string()
+	}
+}
+
 fn compose_params_from_instance_vars(
 	instance_vars: &serde_json::Map<String, serde_json::Value>,
 	parent: Option<&String>,
@@ -61,15 +205,22 @@ fn compose_params_from_instance_vars(
 	/* NOTE: instance vars always dictionary */
 	for (key, value) in instance_vars.iter() {
 		let param = if let Some(parent) = parent {
-			format!("{}.{}", parent, key)
+			format!("{}.{}", parent, quote_instance_var_key(key))
 		} else {
-			key.to_owned()
+			quote_instance_var_key(key)
 		};
 
-		if value.is_object() {
-			params.push(compose_params_from_instance_vars(value.as_object().unwrap(), Some(&param)).unwrap());
+		/* NOTE: only objects are flattened, arrays and scalars are JSON encoded */
+		if let Some(object) = value.as_object() {
+			if let Some(nested) = compose_params_from_instance_vars(object, Some(&param)) {
+				params.push(nested);
+			}
 		} else {
-			params.push(format!("vars.{}={}", &param, &value).replace('"', "%22"));
+			params.push(format!(
+				"vars.{}={}",
+				encode_query_component(&param),
+				encode_query_component(&value.to_string())
+			));
 		}
 	}
 
@@ -80,106 +231,697 @@ fn compose_params_from_instance_vars(
 	}
 }
 
+/// Query string (with the leading `?`) for the instance vars of the pipeline, or an empty string.
+///
+/// Malformed instance vars are ignored rather than failing the put: the link is then merely less precise.
+fn instance_vars_query(instance_vars: Option<&str>) -> String {
+	let Some(instance_vars) = instance_vars else {
+		return "".to_owned();
+	};
+	match serde_json::from_str::<serde_json::Value>(instance_vars) {
+		Ok(serde_json::Value::Object(instance_vars)) => compose_params_from_instance_vars(&instance_vars, None)
+			.map(|params| format!("?{}", params))
+			.unwrap_or_default(),
+		Ok(_) => {
+			eprintln!("⚠️  BUILD_PIPELINE_INSTANCE_VARS is not an object, ignoring it");
+			"".to_owned()
+		},
+		Err(err) => {
+			eprintln!("⚠️  Failed to parse BUILD_PIPELINE_INSTANCE_VARS, ignoring it: {}", err);
+			"".to_owned()
+		},
+	}
+}
+
+/// URL of the Concourse build, with every path segment percent-encoded.
+fn compose_build_url(
+	external_url: &str,
+	team: &str,
+	pipeline: &str,
+	job: &str,
+	build: &str,
+	instance_vars_query: &str,
+) -> Result<String> {
+	let mut url = Url::parse(external_url).with_context(|| anyhow!("ATC_EXTERNAL_URL `{}` is invalid", external_url))?;
+	url.path_segments_mut()
+		.map_err(|_| anyhow!("ATC_EXTERNAL_URL `{}` cannot be a base URL", external_url))?
+		.pop_if_empty()
+		.extend(["teams", team, "pipelines", pipeline, "jobs", job, "builds", build]);
+	Ok(format!("{}{}", url, instance_vars_query))
+}
+
+/// GitLab rejects commit status descriptions longer than this
+const MAX_DESCRIPTION_LENGTH: usize = 255;
+
+/// Trim a description to what GitLab accepts, keeping whole characters.
+fn truncate_description(description: &str) -> String {
+	let description = description.trim();
+	if description.len() <= MAX_DESCRIPTION_LENGTH {
+		return description.to_owned();
+	}
+	let mut end = MAX_DESCRIPTION_LENGTH - '…'.len_utf8();
+	while !description.is_char_boundary(end) {
+		end -= 1;
+	}
+	format!("{}…", &description[..end])
+}
+
+/// Read a file produced by the build, returning `None` when no path is given or the file does not exist.
+fn read_build_file(directory: &str, path: Option<&str>) -> Result<Option<String>> {
+	let Some(path) = path else {
+		return Ok(None);
+	};
+	let path = Path::new(directory).join(path);
+	match std::fs::read_to_string(&path) {
+		Ok(contents) => Ok(Some(contents)),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			eprintln!("`{}` does not exist", path.display());
+			Ok(None)
+		},
+		Err(err) => Err(anyhow!("failed to read `{}`: {}", path.display(), err)),
+	}
+}
+
+/// Summarize all JUnit reports matching the given globs.
+fn summarize_junit_reports(directory: &str, patterns: &[String]) -> Result<reports::TestSummary> {
+	let mut summary = reports::TestSummary::default();
+	for pattern in patterns {
+		let pattern = Path::new(directory).join(pattern);
+		let paths = glob::glob(&pattern.to_string_lossy())
+			.with_context(|| anyhow!("invalid JUnit glob `{}`", pattern.display()))?;
+		for path in paths {
+			let path = path?;
+			let contents = std::fs::read_to_string(&path)
+				.with_context(|| anyhow!("failed to read JUnit report `{}`", path.display()))?;
+			summary
+				.add_report(&contents)
+				.with_context(|| anyhow!("failed to parse JUnit report `{}`", path.display()))?;
+		}
+	}
+	Ok(summary)
+}
+
+/// Create a note on the merge request, or update the one previously created with the same marker.
+fn upsert_sticky_note(client: &Gitlab, project: &str, iid: u64, marker: &str, body: &str) -> Result<()> {
+	let marker = format!("<!-- concourse-gitlab-mr-resource {} -->", marker);
+	let body = format!("{}\n\n{}", body, marker);
+
+	let notes: Vec<Note> =
+		api::paged(notes::MergeRequestNotes::builder().project(project).merge_request(iid).build()?, api::Pagination::All)
+			.query(client)?;
+	if let Some(note) = notes.iter().find(|note| note.body.contains(&marker)) {
+		api::ignore(
+			notes::EditMergeRequestNote::builder()
+				.project(project)
+				.merge_request(iid)
+				.note(note.id)
+				.body(body)
+				.build()?,
+		)
+		.query(client)?;
+	} else {
+		api::ignore(
+			notes::CreateMergeRequestNote::builder()
+				.project(project)
+				.merge_request(iid)
+				.body(body)
+				.build()?,
+		)
+		.query(client)?;
+	}
+	Ok(())
+}
+
+const VERSION_FILE: &str = ".merge-request.json";
+
+fn read_version_file(path: &Path) -> Result<Version> {
+	let file = File::open(path).with_context(|| {
+		anyhow!(
+			"failed to open `{}`; `get` the resource earlier in the job, or set the `iid` param",
+			path.display()
+		)
+	})?;
+	serde_json::from_reader(file).with_context(|| anyhow!("failed to read `{}`", path.display()))
+}
+
+/// Find the version file written by a `get` of this resource among the inputs of the build.
+fn discover_version_file(directory: &str) -> Result<PathBuf> {
+	let mut candidates = Vec::<PathBuf>::new();
+	for entry in std::fs::read_dir(directory).with_context(|| anyhow!("failed to list `{}`", directory))? {
+		let path = entry?.path().join(VERSION_FILE);
+		if path.is_file() {
+			candidates.push(path);
+		}
+	}
+	match candidates.len() {
+		0 => Err(anyhow!(
+			"no input of the build contains `{}`; `get` the resource earlier in the job, or set the `iid` param",
+			VERSION_FILE
+		)),
+		1 => Ok(candidates.remove(0)),
+		_ => Err(anyhow!(
+			"several inputs contain `{}` ({}); set `resource_name` to choose one",
+			VERSION_FILE,
+			candidates.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+		)),
+	}
+}
+
+/// Determine the merge request and commit to act on, from the params or from the version written by `in`.
+fn resolve_version(client: &Gitlab, project: &str, directory: &str, params: &Params) -> Result<Version> {
+	if params.iid.is_none() && params.sha.is_none() {
+		let path = match &params.resource_name {
+			Some(resource_name) => Path::new(directory).join(resource_name).join(VERSION_FILE),
+			None => discover_version_file(directory)?,
+		};
+		eprintln!("Reading version from `{}`", path.display());
+		return read_version_file(&path);
+	}
+
+	let mr: MergeRequest = if let Some(iid) = params.iid {
+		merge_requests::MergeRequest::builder().project(project).merge_request(iid).build()?.query(client)?
+	} else {
+		let sha = params.sha.as_deref().unwrap_or_default();
+		let mrs: Vec<MergeRequest> =
+			commits::MergeRequests::builder().project(project).sha(sha).build()?.query(client)?;
+		mrs.into_iter()
+			.filter(|mr| mr.sha.as_deref() == Some(sha))
+			.min_by_key(|mr| mr.state != "opened")
+			.ok_or_else(|| anyhow!("no merge request has {} as its head", sha))?
+	};
+	let sha = params
+		.sha
+		.clone()
+		.or(mr.sha)
+		.ok_or_else(|| anyhow!("MR {} has null SHA - source branch likely deleted", mr.iid))?;
+	let commit: Commit = commits::Commit::builder().project(mr.source_project_id).commit(&sha).build()?.query(client)?;
+	Ok(Version {
+		iid: mr.iid.to_string(),
+		committed_date: commit.committed_date,
+		sha,
+		trigger: None,
+		kind: None,
+		project: params.project.as_ref().map(|_| mr.project_id.to_string()),
+	})
+}
+
+/// The commits of an MR (newest first, as listed by GitLab) pushed before `sha`, none if `sha` is not one of them.
+fn superseded_commits<'a>(mr_commits: &'a [Commit], sha: &str) -> Vec<&'a Commit> {
+	mr_commits.iter().skip_while(|commit| commit.id != sha).skip(1).collect()
+}
+
+/// Cancel the statuses with the given names which are still pending or running on earlier commits of the MR.
+fn cancel_superseded_statuses(
+	client: &Gitlab,
+	project: &str,
+	mr: &MergeRequest,
+	sha: &str,
+	names: &[&str],
+) -> Result<usize> {
+	let mr_commits: Vec<Commit> = api::paged(
+		merge_requests::MergeRequestCommits::builder().project(project).merge_request(mr.iid).build()?,
+		api::Pagination::All,
+	)
+	.query(client)?;
+
+	let short_sha = &sha[..sha.len().min(8)];
+	let description = format!("Superseded by {}", short_sha);
+	let mut canceled = 0;
+	for commit in superseded_commits(&mr_commits, sha) {
+		for name in names {
+			let statuses: Vec<CommitStatus> = commits::CommitStatuses::builder()
+				.project(mr.source_project_id)
+				.commit(commit.id.as_str())
+				.name(*name)
+				.build()?
+				.query(client)?;
+			for status in statuses.iter().filter(|status| status.status == "pending" || status.status == "running") {
+				eprintln!("Canceling `{}` ({}) on superseded commit {}", name, status.status, commit.id);
+				let mut builder = commits::CreateCommitStatus::builder();
+				builder
+					.project(mr.source_project_id)
+					.commit(commit.id.as_str())
+					.state(commits::CommitStatusState::Canceled)
+					.name(*name)
+					.description(description.as_str());
+				if let Some(ref_name) = &status.ref_name {
+					builder.ref_(ref_name.as_str());
+				}
+				if let Some(target_url) = &status.target_url {
+					builder.target_url(target_url.as_str());
+				}
+				match api::ignore(builder.build()?).query(client) {
+					Ok(()) => canceled += 1,
+					Err(err) => eprintln!("⚠️  Failed to cancel `{}` on {}: {}", name, commit.id, err),
+				}
+			}
+		}
+	}
+	Ok(canceled)
+}
+
+fn parse_status(status: &str) -> Result<commits::CommitStatusState> {
+	match status {
+		"canceled" => Ok(commits::CommitStatusState::Canceled),
+		"running" => Ok(commits::CommitStatusState::Running),
+		"pending" => Ok(commits::CommitStatusState::Pending),
+		"failed" => Ok(commits::CommitStatusState::Failed),
+		"success" => Ok(commits::CommitStatusState::Success),
+		_ => Err(anyhow!("invalid status `{}`", status)),
+	}
+}
+
+/// The error of a finished rebase: GitLab keeps the `merge_error` of an earlier failed rebase, which a rebase that
+/// moved the head did not hit.
+fn rebase_error<'a>(before: &MergeRequest, after: &'a MergeRequest) -> Option<&'a str> {
+	after.merge_error.as_deref().filter(|_| after.sha == before.sha)
+}
+
+/// Request a server-side rebase and wait until GitLab reports it has finished.
+fn rebase_merge_request(client: &Gitlab, project: &str, iid: u64, params: &Params) -> Result<MergeRequest> {
+	let before: MergeRequest =
+		merge_requests::MergeRequest::builder().project(project).merge_request(iid).build()?.query(client)?;
+
+	let mut builder = merge_requests::RebaseMergeRequest::builder();
+	builder.project(project).merge_request(iid);
+	if let Some(skip_ci) = params.skip_ci {
+		builder.skip_ci(skip_ci);
+	}
+	api::ignore(builder.build()?)
+		.query(client)
+		.with_context(|| anyhow!("failed to request rebase of MR {}", iid))?;
+	eprintln!("Rebase of MR {} requested", iid);
+
+	let timeout = Duration::from_secs(params.rebase_timeout.unwrap_or(DEFAULT_REBASE_TIMEOUT_SECONDS));
+	let started = Instant::now();
+	loop {
+		let mr: MergeRequest = merge_requests::MergeRequest::builder()
+			.project(project)
+			.merge_request(iid)
+			.include_rebase_in_progress(true)
+			.build()?
+			.query(client)?;
+
+		if !mr.rebase_in_progress.unwrap_or(false) {
+			if let Some(merge_error) = rebase_error(&before, &mr) {
+				return Err(anyhow!("rebase of MR {} failed: {}", iid, merge_error));
+			}
+			return Ok(mr);
+		}
+		if started.elapsed() >= timeout {
+			return Err(anyhow!("rebase of MR {} still in progress after {}s", iid, timeout.as_secs()));
+		}
+		eprintln!("Rebase of MR {} in progress...", iid);
+		thread::sleep(REBASE_POLL_INTERVAL);
+	}
+}
+
 fn main() -> Result<()> {
 	let args = Args::parse();
 
 	let input: ResourceInput =
 		get_data_from(&mut io::stdin()).map_err(|err| anyhow!("{}", err.downcast::<serde_json::Error>().unwrap()))?;
-	let version: Version = serde_json::from_reader(File::open(
-		Path::new(&args.directory)
-			.join(&input.params.resource_name)
-			.join(".merge-request.json"),
-	)?)
-	.with_context(|| anyhow!("failed to read `.merge-request.json`"))?;
 
 	let uri = Url::parse(&input.source.uri)?;
 	let client = Gitlab::new(uri.host_str().unwrap(), &input.source.private_token)?;
 
+	let project = input
+		.params
+		.project
+		.as_deref()
+		.unwrap_or_else(|| uri.path().trim_start_matches('/').trim_end_matches(".git"));
+	let version = resolve_version(&client, project, &args.directory, &input.params)?;
+	/* versions of a group or several projects know the project of their MR */
+	let project = version.project.clone().unwrap_or_else(|| project.to_owned());
+	let project = project.as_str();
+	let iid = version.iid.parse::<u64>()?;
+
+	if input.params.action == Action::Rebase {
+		let mr = rebase_merge_request(&client, project, iid, &input.params)?;
+		let output = ResourceOutput {
+			version,
+			metadata: vec![
+				Metadata {
+					name: "url".to_owned(),
+					value: mr.web_url,
+				},
+				Metadata {
+					name: "title".to_owned(),
+					value: mr.title,
+				},
+				Metadata {
+					name: "sha".to_owned(),
+					value: mr.sha.unwrap_or_default(),
+				},
+			],
+		};
+		println!("{}", serde_json::to_string_pretty(&output)?);
+		return Ok(());
+	}
+
 	let mr: MergeRequest = merge_requests::MergeRequest::builder()
-		.project(uri.path().trim_start_matches('/').trim_end_matches(".git"))
-		.merge_request(version.iid.parse::<u64>().unwrap())
+		.project(project)
+		.merge_request(iid)
 		.build()?
 		.query(&client)?;
 
-	/* get environment variables */
+	/* get environment variables, templates of every action see the same ones */
 	let build_pipeline_name =
 		env::var("BUILD_PIPELINE_NAME").with_context(|| anyhow!("BUILD_PIPELINE_NAME is not set"))?;
 	let build_job_name = env::var("BUILD_JOB_NAME").with_context(|| anyhow!("BUILD_JOB_NAME is not set"))?;
 	let build_team_name = env::var("BUILD_TEAM_NAME").with_context(|| anyhow!("BUILD_TEAM_NAME is not set"))?;
 	let build_name = env::var("BUILD_NAME").with_context(|| anyhow!("BUILD_NAME is not set"))?;
-	let build_pipeline_instance_vars = match env::var("BUILD_PIPELINE_INSTANCE_VARS") {
-		Ok(v) => {
-			let instance_vars: serde_json::Value = serde_json::from_str(&v).unwrap();
-			format!(
-				"?{}",
-				compose_params_from_instance_vars(instance_vars.as_object().unwrap(), None).unwrap()
-			)
-		},
-		Err(_) => "".to_owned(),
-	};
+	let build_pipeline_instance_vars = instance_vars_query(env::var("BUILD_PIPELINE_INSTANCE_VARS").ok().as_deref());
 
-	let concourse_uri = format!(
-		"{}/teams/{}/pipelines/{}/jobs/{}/builds/{}{}",
-		env::var("ATC_EXTERNAL_URL").with_context(|| anyhow!("ATC_EXTERNAL_URL is not set"))?,
+	let concourse_uri = compose_build_url(
+		&env::var("ATC_EXTERNAL_URL").with_context(|| anyhow!("ATC_EXTERNAL_URL is not set"))?,
 		&build_team_name,
 		&build_pipeline_name,
 		&build_job_name,
 		&build_name,
 		&build_pipeline_instance_vars,
-	);
+	)?;
+
+	let mut context = template::TemplateContext::from_build_env(&args.directory);
+	context
+		.set_merge_request(&mr)
+		.set("MR_SHA", version.sha.as_str())
+		.set("BUILD_PIPELINE_INSTANCE_VARS", build_pipeline_instance_vars.as_str())
+		.set("BUILD_URL", concourse_uri.as_str());
+	let render = |template: &str| context.render(template);
 
 	let pipeline_name = if let Some(pipeline_name) = &input.params.pipeline_name {
-		pipeline_name
-			.clone()
-			.replace("%BUILD_PIPELINE_NAME%", &build_pipeline_name)
-			.replace("%BUILD_JOB_NAME%", &build_job_name)
-			.replace("%BUILD_TEAM_NAME%", &build_team_name)
-			.replace("%BUILD_PIPELINE_INSTANCE_VARS%", &build_pipeline_instance_vars)
+		render(pipeline_name)?
 	} else {
 		format!("{}::{}", build_team_name, build_pipeline_name)
 	};
 
-	let mut builder = commits::CreateCommitStatus::builder();
-	builder
-		.project(mr.source_project_id)
-		.commit(&version.sha)
-		.state(match input.params.status.as_str() {
-			"canceled" => commits::CommitStatusState::Canceled,
-			"running" => commits::CommitStatusState::Running,
-			"pending" => commits::CommitStatusState::Pending,
-			"failed" => commits::CommitStatusState::Failed,
-			"success" => commits::CommitStatusState::Success,
-			_ => panic!("invalid status"),
-		})
-		.name(&pipeline_name)
-		.target_url(&concourse_uri);
-	if let Some(coverage) = input.params.coverage {
-		builder.coverage(coverage);
-	}
-
-	let response: CommitStatusResponce = builder.build()?.query(&client)?;
+	if input.params.action == Action::Review {
+		let review_report = input
+			.params
+			.review_report
+			.as_deref()
+			.ok_or_else(|| anyhow!("`review_report` is required to review a merge request"))?;
+		let contents = read_build_file(&args.directory, Some(review_report))?
+			.ok_or_else(|| anyhow!("review report `{}` does not exist", review_report))?;
+		let findings = reports::parse_findings(&contents, input.params.review_format)?;
 
-	#[allow(clippy::redundant_field_names)]
-	let output = ResourceOutput {
-		version: version,
-		metadata: vec![
+		let diff_refs = mr.diff_refs.as_ref().ok_or_else(|| anyhow!("MR {} has no diff_refs", iid))?;
+		let review_name = input.params.review_name.as_deref().map(render).transpose()?.unwrap_or(pipeline_name);
+		let summary = review::post_findings(
+			&client,
+			project,
+			iid,
+			&review_name,
+			diff_refs,
+			&findings,
+			input.params.max_comments.unwrap_or(DEFAULT_MAX_COMMENTS),
+		)?;
+		eprintln!("{:?}", summary);
+
+		let output = ResourceOutput {
+			version,
+			metadata: vec![
+				Metadata {
+					name: "url".to_owned(),
+					value: mr.web_url,
+				},
+				Metadata {
+					name: "findings".to_owned(),
+					value: summary.total.to_string(),
+				},
+				Metadata {
+					name: "posted".to_owned(),
+					value: summary.posted.to_string(),
+				},
+				Metadata {
+					name: "existing".to_owned(),
+					value: summary.existing.to_string(),
+				},
+				Metadata {
+					name: "resolved".to_owned(),
+					value: summary.resolved.to_string(),
+				},
+				Metadata {
+					name: "outside_diff".to_owned(),
+					value: summary.outside_diff.to_string(),
+				},
+				Metadata {
+					name: "suppressed".to_owned(),
+					value: summary.suppressed.to_string(),
+				},
+				Metadata {
+					name: "failed".to_owned(),
+					value: summary.failed.to_string(),
+				},
+			],
+		};
+		println!("{}", serde_json::to_string_pretty(&output)?);
+		return Ok(());
+	}
+
+	if input.params.action == Action::Deploy || input.params.action == Action::Stop {
+		let environment = context.render(input.params.environment.as_deref().unwrap_or(DEFAULT_ENVIRONMENT))?;
+
+		let mut metadata = vec![
 			Metadata {
 				name: "url".to_owned(),
-				value: mr.web_url,
+				value: mr.web_url.clone(),
 			},
 			Metadata {
-				name: "author".to_owned(),
-				value: mr.author.name,
+				name: "environment".to_owned(),
+				value: environment.clone(),
 			},
-			Metadata {
-				name: "title".to_owned(),
-				value: mr.title,
+		];
+		if input.params.action == Action::Deploy {
+			let status = input
+				.params
+				.deployment_status
+				.as_deref()
+				.ok_or_else(|| anyhow!("`deployment_status` is required to deploy a merge request"))?;
+			let external_url = input.params.environment_url.as_deref().map(|url| context.render(url)).transpose()?;
+			let ref_name = deployments::deployment_ref(&mr, &version)?;
+			let deployment = deployments::deploy(
+				&client,
+				project,
+				&environment,
+				external_url.as_deref(),
+				&version.sha,
+				&ref_name,
+				deployments::parse_deployment_status(status)?,
+			)?;
+			metadata.push(Metadata {
+				name: "deployment_id".to_owned(),
+				value: deployment.id.to_string(),
+			});
+			metadata.push(Metadata {
+				name: "deployment_status".to_owned(),
+				value: deployment.status,
+			});
+		} else {
+			let stopped = deployments::stop(&client, project, &environment)?;
+			metadata.push(Metadata {
+				name: "stopped".to_owned(),
+				value: stopped.to_string(),
+			});
+		}
+
+		let output = ResourceOutput {
+			version,
+			metadata,
+		};
+		println!("{}", serde_json::to_string_pretty(&output)?);
+		return Ok(());
+	}
+
+	input.params.check_statuses()?;
+	let entries = if let Some(statuses_file) = &input.params.statuses_file {
+		let contents = read_build_file(&args.directory, Some(statuses_file))?
+			.ok_or_else(|| anyhow!("statuses file `{}` does not exist", statuses_file))?;
+		Some(
+			serde_json::from_str::<Vec<StatusEntry>>(&contents)
+				.with_context(|| anyhow!("failed to parse statuses file `{}`", statuses_file))?,
+		)
+	} else {
+		input.params.statuses.clone()
+	};
+	let test_summary = match &input.params.junit_paths {
+		Some(patterns) => Some(summarize_junit_reports(&args.directory, patterns)?),
+		None => None,
+	};
+
+	let statuses = if let Some(entries) = entries {
+		if entries.is_empty() {
+			return Err(anyhow!("`statuses` must contain at least one status"));
+		}
+		entries
+			.into_iter()
+			.map(|entry| {
+				Ok(StatusEntry {
+					name: render(&entry.name)?,
+					status: entry.status,
+					description: entry.description.as_deref().map(render).transpose()?.as_deref().map(truncate_description),
+					coverage: entry.coverage,
+					target_url: entry.target_url.as_deref().map(render).transpose()?,
+				})
+			})
+			.collect::<Result<Vec<_>>>()?
+	} else {
+		let status = match read_build_file(&args.directory, input.params.status_file.as_deref())? {
+			Some(contents) => contents.trim().to_owned(),
+			None => input.params.status.clone().ok_or_else(|| {
+				anyhow!("`status`, an existing `status_file` or `statuses` is required to update a commit status")
+			})?,
+		};
+		let coverage = match read_build_file(&args.directory, input.params.coverage_file.as_deref())? {
+			Some(contents) => Some(reports::parse_coverage(&contents).with_context(|| {
+				anyhow!("failed to parse coverage file `{}`", input.params.coverage_file.as_deref().unwrap_or_default())
+			})?),
+			None => input.params.coverage,
+		};
+		let description_file = read_build_file(&args.directory, input.params.description_file.as_deref())?;
+		let description = if let Some(contents) = description_file {
+			Some(truncate_description(&contents))
+		} else {
+			input
+				.params
+				.description
+				.as_deref()
+				.map(render)
+				.transpose()?
+				.as_deref()
+				.map(truncate_description)
+				.or_else(|| test_summary.as_ref().map(|summary| summary.description()))
+		};
+		vec![StatusEntry {
+			name: pipeline_name.clone(),
+			status,
+			description,
+			coverage,
+			target_url: input.params.target_url.as_deref().map(render).transpose()?,
+		}]
+	};
+
+	/* the merged version of an MR is on its target branch, a head built before the merge on the source branch */
+	let ref_name = input.params.ref_name.as_ref().or(if mr.is_merged_version(&version.sha) {
+		Some(&mr.target_branch)
+	} else {
+		mr.source_branch.as_ref()
+	});
+
+	/* an invalid status fails before any status is created */
+	let states = statuses
+		.iter()
+		.map(|entry| parse_status(&entry.status).with_context(|| anyhow!("invalid status of `{}`", entry.name)))
+		.collect::<Result<Vec<_>>>()?;
+
+	let mut results = Vec::<(String, String)>::new();
+	let mut errors = Vec::<String>::new();
+	for (entry, state) in statuses.iter().zip(states) {
+		let mut builder = commits::CreateCommitStatus::builder();
+		builder
+			.project(mr.project_id_of(&version.sha))
+			.commit(&version.sha)
+			.state(state)
+			.name(&entry.name)
+			.target_url(entry.target_url.as_deref().unwrap_or(&concourse_uri));
+		if let Some(coverage) = entry.coverage {
+			builder.coverage(coverage);
+		}
+		if let Some(description) = &entry.description {
+			builder.description(description);
+		}
+		if let Some(ref_name) = ref_name {
+			builder.ref_(ref_name);
+		}
+		if let Some(pipeline_id) = input.params.pipeline_id {
+			builder.pipeline_id(pipeline_id);
+		}
+
+		match builder.build()?.query(&client) {
+			Ok(CommitStatusResponce { status }) => {
+				eprintln!("Set `{}` to {}", entry.name, status);
+				results.push((entry.name.clone(), status));
 			},
-			Metadata {
-				name: "status".to_owned(),
-				value: response.status,
+			Err(err) => {
+				eprintln!("Failed to set `{}` to {}: {}", entry.name, entry.status, err);
+				errors.push(format!("`{}`: {}", entry.name, err));
 			},
-		],
+		}
+	}
+	if !errors.is_empty() {
+		return Err(anyhow!("failed to create commit statuses: {}", errors.join(", ")));
+	}
+
+	let canceled = if input.params.cancel_superseded.unwrap_or(false) {
+		let names: Vec<&str> = statuses.iter().map(|entry| entry.name.as_str()).collect();
+		Some(cancel_superseded_statuses(&client, project, &mr, &version.sha, &names)?)
+	} else {
+		None
+	};
+
+	if let Some(summary) = &test_summary {
+		if input.params.junit_note.unwrap_or(false) {
+			let marker = format!("junit:{}", pipeline_name);
+			upsert_sticky_note(&client, project, iid, &marker, &summary.markdown())?;
+		}
+	}
+
+	if let Some(remove_labels) = &input.params.remove_labels {
+		let present: Vec<&String> = remove_labels.iter().filter(|label| mr.labels.contains(label)).collect();
+		if !present.is_empty() {
+			eprintln!("Removing labels {:?}", present);
+			let mut builder = merge_requests::EditMergeRequest::builder();
+			builder.project(project).merge_request(iid);
+			for label in present {
+				builder.remove_label(label);
+			}
+			api::ignore(builder.build()?).query(&client)?;
+		}
+	}
+
+	let mut metadata = vec![
+		Metadata {
+			name: "url".to_owned(),
+			value: mr.web_url,
+		},
+		Metadata {
+			name: "author".to_owned(),
+			value: mr.author.name,
+		},
+		Metadata {
+			name: "title".to_owned(),
+			value: mr.title,
+		},
+	];
+	if input.params.statuses.is_none() && input.params.statuses_file.is_none() {
+		metadata.push(Metadata {
+			name: "status".to_owned(),
+			value: results.remove(0).1,
+		});
+	} else {
+		metadata.extend(results.into_iter().map(|(name, status)| Metadata {
+			name: format!("status:{}", name),
+			value: status,
+		}));
+	}
+	if let Some(canceled) = canceled {
+		metadata.push(Metadata {
+			name: "canceled".to_owned(),
+			value: canceled.to_string(),
+		});
+	}
+	if let Some(summary) = &test_summary {
+		metadata.push(Metadata {
+			name: "tests".to_owned(),
+			value: summary.description(),
+		});
+	}
+
+	#[allow(clippy::redundant_field_names)]
+	let output = ResourceOutput {
+		version: version,
+		metadata: metadata,
 	};
 	println!("{}", serde_json::to_string_pretty(&output)?);
 	Ok(())
@@ -259,4 +1001,228 @@ mod compose_params_from_instance_vars_tests {
 		let url = compose_params_from_instance_vars(json.as_object().unwrap(), None);
 		assert!(url == Some("vars.a=0&vars.b.a=0&vars.b.b=true&vars.c=%220-0%22".to_owned()));
 	}
+
+	#[test]
+	fn test_generate_url_with_special_characters() {
+		let json = serde_json::json!({ "branch": "feature/a b&c#d", "name": "日本" });
+		let url = compose_params_from_instance_vars(json.as_object().unwrap(), None);
+		assert_eq!(
+			url,
+			Some("vars.branch=%22feature%2Fa+b%26c%23d%22&vars.name=%22%E6%97%A5%E6%9C%AC%22".to_owned())
+		);
+	}
+
+	#[test]
+	fn test_generate_url_with_array_and_null() {
+		let json = serde_json::json!({ "list": [1, "a"], "none": null });
+		let url = compose_params_from_instance_vars(json.as_object().unwrap(), None);
+		assert_eq!(url, Some("vars.list=%5B1%2C%22a%22%5D&vars.none=null".to_owned()));
+	}
+
+	#[test]
+	fn test_generate_url_with_quoted_key() {
+		let json = serde_json::json!({ "a.b": { "c d": 1 } });
+		let url = compose_params_from_instance_vars(json.as_object().unwrap(), None);
+		assert_eq!(url, Some("vars.%22a.b%22.%22c+d%22=1".to_owned()));
+	}
+
+	#[test]
+	fn test_generate_url_with_empty_nested_parameter() {
+		let json = serde_json::json!({ "a": {}, "b": 1 });
+		let url = compose_params_from_instance_vars(json.as_object().unwrap(), None);
+		assert_eq!(url, Some("vars.b=1".to_owned()));
+	}
+
+	#[test]
+	fn test_instance_vars_query() {
+		assert_eq!(instance_vars_query(None), "");
+		assert_eq!(instance_vars_query(Some("not json")), "");
+		assert_eq!(instance_vars_query(Some("[1]")), "");
+		assert_eq!(instance_vars_query(Some("{}")), "");
+		assert_eq!(instance_vars_query(Some(r#"{"a":1}"#)), "?vars.a=1");
+	}
+
+	#[test]
+	fn test_compose_build_url() {
+		assert_eq!(
+			compose_build_url("https://ci.example.com/", "main", "my pipeline", "build/test", "42", "?vars.a=1").unwrap(),
+			"https://ci.example.com/teams/main/pipelines/my%20pipeline/jobs/build%2Ftest/builds/42?vars.a=1"
+		);
+		assert_eq!(
+			compose_build_url("https://ci.example.com/concourse", "main", "p", "j", "1.1", "").unwrap(),
+			"https://ci.example.com/concourse/teams/main/pipelines/p/jobs/j/builds/1.1"
+		);
+	}
+}
+
+#[cfg(test)]
+mod discover_version_file_tests {
+	use super::*;
+
+	fn build_directory(name: &str, inputs: &[&str]) -> PathBuf {
+		let directory = env::temp_dir().join(format!("gitlab-mr-out-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		std::fs::create_dir_all(directory.join("source-code")).unwrap();
+		for input in inputs {
+			std::fs::create_dir_all(directory.join(input)).unwrap();
+			std::fs::write(directory.join(input).join(VERSION_FILE), "{}").unwrap();
+		}
+		directory
+	}
+
+	#[test]
+	fn test_discover_single_input() {
+		let directory = build_directory("single", &["mr"]);
+		let path = discover_version_file(directory.to_str().unwrap()).unwrap();
+		assert_eq!(path, directory.join("mr").join(VERSION_FILE));
+	}
+
+	#[test]
+	fn test_discover_no_input() {
+		let directory = build_directory("none", &[]);
+		assert!(discover_version_file(directory.to_str().unwrap()).is_err());
+	}
+
+	#[test]
+	fn test_discover_ambiguous_inputs() {
+		let directory = build_directory("ambiguous", &["mr-a", "mr-b"]);
+		let err = discover_version_file(directory.to_str().unwrap()).unwrap_err();
+		assert!(err.to_string().contains("resource_name"));
+	}
+}
+
+#[cfg(test)]
+mod truncate_description_tests {
+	use super::*;
+
+	#[test]
+	fn test_short_description_is_kept() {
+		assert_eq!(truncate_description("  12 passed, 0 failed\n"), "12 passed, 0 failed");
+	}
+
+	#[test]
+	fn test_long_description_is_truncated() {
+		let description = "é".repeat(200);
+		let truncated = truncate_description(&description);
+		assert!(truncated.len() <= MAX_DESCRIPTION_LENGTH);
+		assert!(truncated.ends_with('…'));
+	}
+}
+
+#[cfg(test)]
+mod parse_status_tests {
+	use super::*;
+	use rstest::rstest;
+
+	#[rstest]
+	#[case::success("success", commits::CommitStatusState::Success)]
+	#[case::failed("failed", commits::CommitStatusState::Failed)]
+	#[case::running("running", commits::CommitStatusState::Running)]
+	#[case::pending("pending", commits::CommitStatusState::Pending)]
+	#[case::canceled("canceled", commits::CommitStatusState::Canceled)]
+	fn test_parse_status(#[case] status: &str, #[case] expect: commits::CommitStatusState) {
+		assert_eq!(parse_status(status).unwrap(), expect);
+	}
+
+	#[test]
+	fn test_parse_invalid_status() {
+		assert!(parse_status("green").is_err());
+	}
+}
+
+#[cfg(test)]
+mod superseded_commits_tests {
+	use super::*;
+
+	fn commits(ids: &[&str]) -> Vec<Commit> {
+		ids.iter()
+			.map(|id| Commit {
+				id: id.to_string(),
+				committed_date: "2025-01-01T00:00:00Z".to_owned(),
+				message: String::new(),
+			})
+			.collect()
+	}
+
+	fn ids(commits: Vec<&Commit>) -> Vec<&str> {
+		commits.into_iter().map(|commit| commit.id.as_str()).collect()
+	}
+
+	#[test]
+	fn test_only_earlier_commits_are_superseded() {
+		let commits = commits(&["c", "b", "a"]);
+		assert_eq!(ids(superseded_commits(&commits, "c")), vec!["b", "a"]);
+		/* a build of `b` finishing after `c` was pushed leaves `c` alone */
+		assert_eq!(ids(superseded_commits(&commits, "b")), vec!["a"]);
+	}
+
+	#[test]
+	fn test_unknown_commit_supersedes_nothing() {
+		assert!(superseded_commits(&commits(&["b", "a"]), "x").is_empty());
+	}
+}
+
+#[cfg(test)]
+mod rebase_error_tests {
+	use super::*;
+
+	fn merge_request(sha: &str, merge_error: Option<&str>) -> MergeRequest {
+		serde_json::from_value(serde_json::json!({
+			"iid": 1,
+			"title": "title",
+			"state": "opened",
+			"labels": [],
+			"sha": sha,
+			"author": { "id": 1, "name": "A", "username": "a" },
+			"updated_at": "2025-01-01T00:00:00Z",
+			"project_id": 1,
+			"source_project_id": 1,
+			"source_branch": "feature",
+			"target_branch": "main",
+			"web_url": "https://gitlab.com/a/b/-/merge_requests/1",
+			"merge_error": merge_error,
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn test_failed_rebase_keeps_head() {
+		let before = merge_request("a", None);
+		assert_eq!(rebase_error(&before, &merge_request("a", Some("conflict"))), Some("conflict"));
+	}
+
+	#[test]
+	fn test_error_of_earlier_rebase_is_ignored() {
+		let before = merge_request("a", Some("conflict"));
+		assert_eq!(rebase_error(&before, &merge_request("b", Some("conflict"))), None);
+		assert_eq!(rebase_error(&before, &merge_request("b", None)), None);
+	}
+}
+
+#[cfg(test)]
+mod check_statuses_tests {
+	use super::*;
+
+	fn params(params: serde_json::Value) -> Params {
+		serde_json::from_value(params).unwrap()
+	}
+
+	#[test]
+	fn test_single_status_params_are_rejected_with_statuses() {
+		let params = params(serde_json::json!({
+			"statuses": [{ "name": "unit", "status": "success" }],
+			"status": "failed",
+			"description_file": "summary.txt",
+		}));
+		let err = params.check_statuses().unwrap_err().to_string();
+		assert!(err.contains("`status`, `description_file`"), "{}", err);
+	}
+
+	#[test]
+	fn test_single_status_params_without_statuses() {
+		assert!(params(serde_json::json!({ "status": "success", "description": "ok" })).check_statuses().is_ok());
+		assert!(params(serde_json::json!({ "statuses_file": "statuses.json", "junit_paths": ["*.xml"] }))
+			.check_statuses()
+			.is_ok());
+	}
 }
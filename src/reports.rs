@@ -0,0 +1,550 @@
+//! Parsers for report files produced by the build and consumed by `out`.
+use anyhow::{
+	anyhow,
+	Context,
+	Result,
+};
+use serde::Deserialize;
+
+/// A single static-analysis finding, normalized across report formats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+	pub path: String,
+	pub line: u64,
+	pub severity: String,
+	pub rule: Option<String>,
+	pub message: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+	Sarif,
+	Checkstyle,
+	CodeClimate,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sarif {
+	runs: Vec<SarifRun>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SarifRun {
+	#[serde(default)]
+	results: Vec<SarifResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+	rule_id: Option<String>,
+	level: Option<String>,
+	message: SarifMessage,
+	#[serde(default)]
+	locations: Vec<SarifLocation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SarifMessage {
+	#[serde(default)]
+	text: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifLocation {
+	physical_location: Option<SarifPhysicalLocation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifPhysicalLocation {
+	artifact_location: SarifArtifactLocation,
+	region: Option<SarifRegion>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SarifArtifactLocation {
+	uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifRegion {
+	start_line: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Checkstyle {
+	#[serde(rename = "file", default)]
+	files: Vec<CheckstyleFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckstyleFile {
+	#[serde(rename = "@name")]
+	name: String,
+	#[serde(rename = "error", default)]
+	errors: Vec<CheckstyleError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckstyleError {
+	#[serde(rename = "@line")]
+	line: Option<u64>,
+	#[serde(rename = "@severity")]
+	severity: Option<String>,
+	#[serde(rename = "@message")]
+	message: String,
+	#[serde(rename = "@source")]
+	source: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeClimateIssue {
+	description: String,
+	check_name: Option<String>,
+	severity: Option<String>,
+	location: CodeClimateLocation,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeClimateLocation {
+	path: String,
+	lines: Option<CodeClimateLines>,
+	positions: Option<CodeClimatePositions>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeClimateLines {
+	begin: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeClimatePositions {
+	begin: CodeClimatePosition,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeClimatePosition {
+	line: u64,
+}
+
+/// Guess the format of a report from its contents.
+fn detect_report_format(contents: &str) -> ReportFormat {
+	match contents.trim_start().chars().next() {
+		Some('<') => ReportFormat::Checkstyle,
+		Some('[') => ReportFormat::CodeClimate,
+		_ => ReportFormat::Sarif,
+	}
+}
+
+/// Parse the findings of a SARIF, Checkstyle XML or Code Climate JSON report.
+///
+/// Findings without a line number cannot be placed on the diff and are dropped.
+pub fn parse_findings(contents: &str, format: Option<ReportFormat>) -> Result<Vec<Finding>> {
+	let findings = match format.unwrap_or_else(|| detect_report_format(contents)) {
+		ReportFormat::Sarif => {
+			let report: Sarif =
+				serde_json::from_str(contents).with_context(|| anyhow!("failed to parse SARIF report"))?;
+			report
+				.runs
+				.into_iter()
+				.flat_map(|run| run.results)
+				.filter_map(|result| {
+					let location = result.locations.into_iter().find_map(|location| location.physical_location)?;
+					Some(Finding {
+						path: location.artifact_location.uri,
+						line: location.region?.start_line?,
+						severity: result.level.unwrap_or_else(|| "warning".to_owned()),
+						rule: result.rule_id,
+						message: result.message.text,
+					})
+				})
+				.collect()
+		},
+		ReportFormat::Checkstyle => {
+			let report: Checkstyle =
+				quick_xml::de::from_str(contents).with_context(|| anyhow!("failed to parse Checkstyle report"))?;
+			report
+				.files
+				.into_iter()
+				.flat_map(|file| {
+					let name = file.name;
+					file.errors.into_iter().filter_map(move |error| {
+						Some(Finding {
+							path: name.clone(),
+							line: error.line?,
+							severity: error.severity.unwrap_or_else(|| "warning".to_owned()),
+							rule: error.source,
+							message: error.message,
+						})
+					})
+				})
+				.collect()
+		},
+		ReportFormat::CodeClimate => {
+			let report: Vec<CodeClimateIssue> =
+				serde_json::from_str(contents).with_context(|| anyhow!("failed to parse Code Climate report"))?;
+			report
+				.into_iter()
+				.filter_map(|issue| {
+					let line = issue
+						.location
+						.lines
+						.map(|lines| lines.begin)
+						.or(issue.location.positions.map(|positions| positions.begin.line))?;
+					Some(Finding {
+						path: issue.location.path,
+						line,
+						severity: issue.severity.unwrap_or_else(|| "minor".to_owned()),
+						rule: issue.check_name,
+						message: issue.description,
+					})
+				})
+				.collect()
+		},
+	};
+	Ok(findings)
+}
+
+/// Root element of a Cobertura XML report.
+#[derive(Debug, Deserialize)]
+struct Cobertura {
+	#[serde(rename = "@line-rate")]
+	line_rate: f64,
+}
+
+/// Parse a coverage percentage from a plain number, a Cobertura XML report or an lcov tracefile/summary.
+pub fn parse_coverage(contents: &str) -> Result<f64> {
+	let contents = contents.trim();
+
+	if contents.starts_with('<') {
+		let report: Cobertura =
+			quick_xml::de::from_str(contents).with_context(|| anyhow!("failed to parse Cobertura report"))?;
+		return Ok(report.line_rate * 100.0);
+	}
+
+	/* lcov tracefile: sum of lines found (LF) and lines hit (LH) across all records */
+	if contents.lines().any(|line| line.starts_with("LF:")) {
+		let (mut found, mut hit) = (0u64, 0u64);
+		for line in contents.lines() {
+			if let Some(value) = line.strip_prefix("LF:") {
+				found += value.trim().parse::<u64>()?;
+			} else if let Some(value) = line.strip_prefix("LH:") {
+				hit += value.trim().parse::<u64>()?;
+			}
+		}
+		if found == 0 {
+			return Err(anyhow!("lcov tracefile has no instrumented lines"));
+		}
+		return Ok(hit as f64 * 100.0 / found as f64);
+	}
+
+	/* `lcov --summary` output, e.g. `  lines......: 85.3% (1234 of 1446 lines)` */
+	if let Some(line) = contents.lines().find(|line| line.trim_start().starts_with("lines.")) {
+		let value = line
+			.split(':')
+			.nth(1)
+			.and_then(|value| value.split('%').next())
+			.ok_or_else(|| anyhow!("malformed lcov summary line `{}`", line))?;
+		return value.trim().parse::<f64>().with_context(|| anyhow!("malformed lcov summary line `{}`", line));
+	}
+
+	contents
+		.trim_end_matches('%')
+		.trim()
+		.parse::<f64>()
+		.with_context(|| anyhow!("unrecognized coverage format"))
+}
+
+/// A `<testsuites>` or `<testsuite>` element; suites may nest.
+#[derive(Debug, Deserialize)]
+struct JUnitSuite {
+	#[serde(rename = "testsuite", default)]
+	suites: Vec<JUnitSuite>,
+	#[serde(rename = "testcase", default)]
+	cases: Vec<JUnitCase>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JUnitCase {
+	#[serde(rename = "@name")]
+	name: String,
+	#[serde(rename = "@classname")]
+	classname: Option<String>,
+	#[serde(rename = "@time")]
+	time: Option<f64>,
+	failure: Option<JUnitFailure>,
+	error: Option<JUnitFailure>,
+	skipped: Option<JUnitFailure>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JUnitFailure {
+	#[serde(rename = "@message")]
+	message: Option<String>,
+	#[serde(rename = "$text")]
+	text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedTest {
+	pub name: String,
+	pub message: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TestSummary {
+	pub total: usize,
+	pub failed: Vec<FailedTest>,
+	pub skipped: usize,
+	/// Slowest tests first
+	pub slowest: Vec<(String, f64)>,
+}
+
+/// Number of slowest tests kept in a summary
+const SLOWEST_TESTS: usize = 5;
+
+impl TestSummary {
+	fn add_suite(&mut self, suite: JUnitSuite) {
+		for suite in suite.suites {
+			self.add_suite(suite);
+		}
+		for case in suite.cases {
+			let name = match case.classname {
+				Some(classname) if !classname.is_empty() => format!("{}.{}", classname, case.name),
+				_ => case.name,
+			};
+			self.total += 1;
+			if let Some(failure) = case.failure.or(case.error) {
+				let message = failure.message.or(failure.text).unwrap_or_default();
+				self.failed.push(FailedTest {
+					name: name.clone(),
+					message: message.trim().lines().next().unwrap_or_default().to_owned(),
+				});
+			} else if case.skipped.is_some() {
+				self.skipped += 1;
+			}
+			if let Some(time) = case.time {
+				self.slowest.push((name, time));
+			}
+		}
+	}
+
+	/// Add the test cases of a JUnit XML report.
+	pub fn add_report(&mut self, contents: &str) -> Result<()> {
+		let suite: JUnitSuite =
+			quick_xml::de::from_str(contents).with_context(|| anyhow!("failed to parse JUnit report"))?;
+		self.add_suite(suite);
+		self.slowest.sort_by(|a, b| b.1.total_cmp(&a.1));
+		self.slowest.truncate(SLOWEST_TESTS);
+		Ok(())
+	}
+
+	/// One line summary, e.g. `120 tests, 2 failed, 3 skipped`.
+	pub fn description(&self) -> String {
+		format!("{} tests, {} failed, {} skipped", self.total, self.failed.len(), self.skipped)
+	}
+
+	/// Markdown report listing failing and slowest tests.
+	pub fn markdown(&self) -> String {
+		let mut markdown = format!("**Test results:** {}\n", self.description());
+		if !self.failed.is_empty() {
+			markdown.push_str("\n| Failed test | Message |\n| --- | --- |\n");
+			for test in &self.failed {
+				markdown.push_str(&format!(
+					"| `{}` | {} |\n",
+					test.name,
+					test.message.replace('|', "\\|")
+				));
+			}
+		}
+		if !self.slowest.is_empty() {
+			markdown.push_str("\n| Slowest test | Time |\n| --- | --- |\n");
+			for (name, time) in &self.slowest {
+				markdown.push_str(&format!("| `{}` | {:.2}s |\n", name, time));
+			}
+		}
+		markdown
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse_coverage;
+	use rstest::rstest;
+
+	#[rstest]
+	#[case::plain_number("85.5\n", 85.5)]
+	#[case::percentage("85.5%", 85.5)]
+	#[case::cobertura(
+		r#"<?xml version="1.0" ?>
+<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">
+<coverage line-rate="0.75" branch-rate="0.5" version="1.9" timestamp="1">
+	<packages/>
+</coverage>"#,
+		75.0
+	)]
+	#[case::lcov_tracefile("TN:\nSF:a.rs\nLF:10\nLH:5\nend_of_record\nSF:b.rs\nLF:30\nLH:25\nend_of_record\n", 75.0)]
+	#[case::lcov_summary(
+		"Reading tracefile lcov.info\nSummary coverage rate:\n  lines......: 85.3% (1234 of 1446 lines)\n  functions..: 80.0% (8 of 10 functions)\n",
+		85.3
+	)]
+	fn test_parse_coverage(#[case] contents: &str, #[case] expect: f64) {
+		assert!((parse_coverage(contents).unwrap() - expect).abs() < 1e-9);
+	}
+
+	#[test]
+	fn test_parse_coverage_garbage() {
+		assert!(parse_coverage("no coverage here").is_err());
+	}
+}
+
+#[cfg(test)]
+mod findings_tests {
+	use super::{
+		parse_findings,
+		Finding,
+		ReportFormat,
+	};
+
+	fn finding(path: &str, line: u64, severity: &str, rule: &str, message: &str) -> Finding {
+		Finding {
+			path: path.to_owned(),
+			line,
+			severity: severity.to_owned(),
+			rule: Some(rule.to_owned()),
+			message: message.to_owned(),
+		}
+	}
+
+	#[test]
+	fn test_parse_sarif() {
+		let report = r#"{
+			"version": "2.1.0",
+			"runs": [{
+				"tool": { "driver": { "name": "clippy" } },
+				"results": [
+					{
+						"ruleId": "clippy::needless_return",
+						"level": "warning",
+						"message": { "text": "unneeded `return` statement" },
+						"locations": [{
+							"physicalLocation": {
+								"artifactLocation": { "uri": "src/main.rs" },
+								"region": { "startLine": 12, "startColumn": 5 }
+							}
+						}]
+					},
+					{
+						"ruleId": "no-location",
+						"message": { "text": "project-wide finding" }
+					}
+				]
+			}]
+		}"#;
+		assert_eq!(
+			parse_findings(report, None).unwrap(),
+			vec![finding("src/main.rs", 12, "warning", "clippy::needless_return", "unneeded `return` statement")]
+		);
+	}
+
+	#[test]
+	fn test_parse_checkstyle() {
+		let report = r#"<?xml version="1.0" encoding="UTF-8"?>
+<checkstyle version="4.3">
+	<file name="/build/src/app.js">
+		<error line="3" column="1" severity="error" message="Unexpected var" source="no-var"/>
+		<error line="9" severity="warning" message="Missing semicolon" source="semi"/>
+	</file>
+	<file name="/build/src/empty.js"/>
+</checkstyle>"#;
+		assert_eq!(
+			parse_findings(report, Some(ReportFormat::Checkstyle)).unwrap(),
+			vec![
+				finding("/build/src/app.js", 3, "error", "no-var", "Unexpected var"),
+				finding("/build/src/app.js", 9, "warning", "semi", "Missing semicolon"),
+			]
+		);
+	}
+
+	#[test]
+	fn test_parse_code_climate() {
+		let report = r#"[
+			{
+				"description": "Method `foo` has a Cognitive Complexity of 12",
+				"check_name": "method_complexity",
+				"fingerprint": "abc",
+				"severity": "major",
+				"location": { "path": "lib/foo.rb", "lines": { "begin": 4, "end": 20 } }
+			},
+			{
+				"description": "Similar blocks of code found",
+				"check_name": "similar-code",
+				"location": { "path": "lib/bar.rb", "positions": { "begin": { "line": 7, "column": 1 } } }
+			}
+		]"#;
+		assert_eq!(
+			parse_findings(report, None).unwrap(),
+			vec![
+				finding("lib/foo.rb", 4, "major", "method_complexity", "Method `foo` has a Cognitive Complexity of 12"),
+				finding("lib/bar.rb", 7, "minor", "similar-code", "Similar blocks of code found"),
+			]
+		);
+	}
+}
+
+#[cfg(test)]
+mod junit_tests {
+	use super::{
+		FailedTest,
+		TestSummary,
+	};
+
+	#[test]
+	fn test_junit_summary() {
+		let mut summary = TestSummary::default();
+		summary
+			.add_report(
+				r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites tests="4" failures="1">
+	<testsuite name="unit" tests="3">
+		<properties><property name="a" value="b"/></properties>
+		<testcase name="adds" classname="math" time="0.5"/>
+		<testcase name="divides" classname="math" time="2.25">
+			<failure message="expected 2, got 3" type="AssertionError">stack trace</failure>
+		</testcase>
+		<testcase name="later" classname="math"><skipped/></testcase>
+		<system-out>noise</system-out>
+	</testsuite>
+</testsuites>"#,
+			)
+			.unwrap();
+		summary
+			.add_report(
+				r#"<testsuite name="it"><testcase name="boots" time="1"><error>connection refused
+at main</error></testcase></testsuite>"#,
+			)
+			.unwrap();
+
+		assert_eq!(summary.total, 4);
+		assert_eq!(summary.skipped, 1);
+		assert_eq!(
+			summary.failed,
+			vec![
+				FailedTest {
+					name: "math.divides".to_owned(),
+					message: "expected 2, got 3".to_owned(),
+				},
+				FailedTest {
+					name: "boots".to_owned(),
+					message: "connection refused".to_owned(),
+				},
+			]
+		);
+		assert_eq!(summary.slowest.first(), Some(&("math.divides".to_owned(), 2.25)));
+		assert_eq!(summary.description(), "4 tests, 2 failed, 1 skipped");
+	}
+}
@@ -0,0 +1,341 @@
+//! Post static-analysis findings as merge request diff discussions.
+use crate::common::{
+	Diff,
+	DiffRefs,
+	Discussion,
+};
+use crate::endpoints::ResolveMergeRequestDiscussion;
+use crate::reports::Finding;
+use anyhow::Result;
+use gitlab::api::{
+	self,
+	paged,
+	projects::merge_requests::{
+		discussions::{
+			CreateMergeRequestDiscussion,
+			MergeRequestDiscussions,
+			Position,
+			TextPosition,
+		},
+		MergeRequestDiffs,
+	},
+	Pagination,
+	Query,
+};
+use gitlab::Gitlab;
+use std::collections::{
+	HashMap,
+	HashSet,
+};
+
+/// Hidden marker identifying discussions opened by this resource, followed by the fingerprint and the review name
+const FINDING_MARKER: &str = "<!-- concourse-gitlab-mr-resource finding:";
+const REVIEW_MARKER: &str = " review:";
+
+#[derive(Debug, Default)]
+pub struct ReviewSummary {
+	/// Findings in the report which could be placed on a line
+	pub total: usize,
+	/// Findings on lines the merge request does not touch
+	pub outside_diff: usize,
+	/// Findings dropped because of `max_comments`
+	pub suppressed: usize,
+	/// Findings which already had a discussion from a previous run
+	pub existing: usize,
+	pub posted: usize,
+	/// Findings GitLab refused to comment on (e.g. a position it rejects)
+	pub failed: usize,
+	pub resolved: usize,
+}
+
+/// A file changed by the merge request.
+#[derive(Debug, Default)]
+pub struct ChangedFile {
+	/// Path before the merge request, differs from the new path for renamed files
+	pub old_path: String,
+	/// Contents of the lines added or modified, by line number in the new file
+	pub lines: HashMap<u64, String>,
+}
+
+/// Lines added or modified by the merge request, by new file path.
+pub fn changed_lines(diffs: &[Diff]) -> HashMap<String, ChangedFile> {
+	let mut changed = HashMap::<String, ChangedFile>::new();
+	for diff in diffs.iter().filter(|diff| !diff.deleted_file) {
+		let file = changed.entry(diff.new_path.clone()).or_default();
+		file.old_path = diff.old_path.clone();
+		let lines = &mut file.lines;
+		let mut new_line = 0u64;
+		for line in diff.diff.lines() {
+			if let Some(hunk) = line.strip_prefix("@@ ") {
+				/* @@ -old_start,old_count +new_start,new_count @@ */
+				new_line = hunk
+					.split_whitespace()
+					.find_map(|range| range.strip_prefix('+'))
+					.and_then(|range| range.split(',').next())
+					.and_then(|start| start.parse().ok())
+					.unwrap_or(0);
+			} else if let Some(contents) = line.strip_prefix('+') {
+				lines.insert(new_line, contents.to_owned());
+				new_line += 1;
+			} else if !line.starts_with('-') && !line.starts_with('\\') {
+				new_line += 1;
+			}
+		}
+	}
+	changed
+}
+
+/// Match a report path (often absolute, or a `file://` URI) against the paths changed by the merge request.
+fn resolve_path<'a>(path: &str, changed: &'a HashMap<String, ChangedFile>) -> Option<&'a String> {
+	let path = path.trim_start_matches("file://").trim_start_matches("./");
+	changed
+		.keys()
+		.filter(|changed_path| path == changed_path.as_str() || path.ends_with(&format!("/{}", changed_path)))
+		.max_by_key(|changed_path| changed_path.len())
+}
+
+fn severity_rank(severity: &str) -> u8 {
+	match severity.to_lowercase().as_str() {
+		"blocker" | "critical" | "error" => 0,
+		"major" | "warning" => 1,
+		"minor" => 2,
+		_ => 3,
+	}
+}
+
+/// Stable identifier of a finding, used to recognize it across runs (FNV-1a).
+///
+/// The line number is left out, so that a finding moved by edits above it is the same finding. The code of its line,
+/// with whitespace normalized, tells apart the same finding on different lines.
+fn fingerprint(path: &str, finding: &Finding, code: &str) -> String {
+	let code = code.split_whitespace().collect::<Vec<_>>().join(" ");
+	let key = format!("{}\0{}\0{}\0{}", path, finding.rule.as_deref().unwrap_or(""), finding.message, code);
+	let hash = key
+		.bytes()
+		.fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+	format!("{:016x}", hash)
+}
+
+fn discussion_body(finding: &Finding, fingerprint: &str, review: &str) -> String {
+	let rule = finding.rule.as_ref().map(|rule| format!(" `{}`", rule)).unwrap_or_default();
+	format!(
+		"**{}**{}: {}\n\n{}{}{}{} -->",
+		finding.severity, rule, finding.message, FINDING_MARKER, fingerprint, REVIEW_MARKER, review
+	)
+}
+
+/// The fingerprint and review name of a discussion opened by this resource.
+fn marker_of(discussion: &Discussion) -> Option<(&str, &str)> {
+	let body = &discussion.notes.first()?.body;
+	let start = body.find(FINDING_MARKER)? + FINDING_MARKER.len();
+	let marker = &body[start..];
+	marker[..marker.find("-->")?].trim_end().split_once(REVIEW_MARKER)
+}
+
+/// Post findings on the lines changed by the merge request.
+///
+/// Findings which already have a discussion from a previous run are not posted again, and unresolved discussions
+/// whose finding has disappeared are resolved. Only the discussions of the same `review` are considered, so that
+/// several reports (e.g. of different tools) can be reviewed on the same merge request.
+pub fn post_findings(
+	client: &Gitlab,
+	project: &str,
+	iid: u64,
+	review: &str,
+	diff_refs: &DiffRefs,
+	findings: &[Finding],
+	max_comments: usize,
+) -> Result<ReviewSummary> {
+	let diffs: Vec<Diff> = paged(MergeRequestDiffs::builder().project(project).merge_request(iid).build()?, Pagination::All)
+		.query(client)?;
+	let changed = changed_lines(&diffs);
+
+	let mut summary = ReviewSummary {
+		total: findings.len(),
+		..Default::default()
+	};
+
+	let mut in_diff = Vec::<(&String, &Finding, String)>::new();
+	for finding in findings {
+		let code = resolve_path(&finding.path, &changed)
+			.and_then(|path| Some((path, changed[path].lines.get(&finding.line)?)));
+		match code {
+			Some((path, code)) => {
+				let fingerprint = fingerprint(path, finding, code);
+				if !in_diff.iter().any(|(_, _, existing)| *existing == fingerprint) {
+					in_diff.push((path, finding, fingerprint));
+				}
+			},
+			None => summary.outside_diff += 1,
+		}
+	}
+	in_diff.sort_by(|a, b| {
+		severity_rank(&a.1.severity)
+			.cmp(&severity_rank(&b.1.severity))
+			.then_with(|| a.0.cmp(b.0))
+			.then_with(|| a.1.line.cmp(&b.1.line))
+	});
+	let current: HashSet<&String> = in_diff.iter().map(|(_, _, fingerprint)| fingerprint).collect();
+
+	let discussions: Vec<Discussion> = paged(
+		MergeRequestDiscussions::builder().project(project).merge_request(iid).build()?,
+		Pagination::All,
+	)
+	.query(client)?;
+	let mut previous = HashSet::<String>::new();
+	for discussion in &discussions {
+		let Some((marker, discussion_review)) = marker_of(discussion) else {
+			continue;
+		};
+		if discussion_review != review {
+			continue;
+		}
+		previous.insert(marker.to_owned());
+		let resolved = discussion.notes.first().and_then(|note| note.resolved).unwrap_or(true);
+		if !resolved && !current.contains(&marker.to_owned()) {
+			eprintln!("Resolving discussion {} (finding no longer reported)", discussion.id);
+			api::ignore(ResolveMergeRequestDiscussion {
+				project: project.into(),
+				merge_request: iid,
+				discussion: discussion.id.as_str().into(),
+				resolved: true,
+			})
+			.query(client)?;
+			summary.resolved += 1;
+		}
+	}
+
+	for (path, finding, fingerprint) in &in_diff {
+		if previous.contains(fingerprint) {
+			summary.existing += 1;
+			continue;
+		}
+		if summary.posted >= max_comments {
+			summary.suppressed += 1;
+			continue;
+		}
+
+		let position = Position::builder()
+			.base_sha(diff_refs.base_sha.as_str())
+			.start_sha(diff_refs.start_sha.as_str())
+			.head_sha(diff_refs.head_sha.as_str())
+			.text_position(
+				TextPosition::builder()
+					.new_path(path.as_str())
+					.old_path(changed[*path].old_path.as_str())
+					.new_line(finding.line)
+					.build()?,
+			)
+			.build()?;
+		eprintln!("Commenting on {}:{}: {}", path, finding.line, finding.message);
+		let posted = api::ignore(
+			CreateMergeRequestDiscussion::builder()
+				.project(project)
+				.merge_request(iid)
+				.body(discussion_body(finding, fingerprint, review))
+				.position(position)
+				.build()?,
+		)
+		.query(client);
+		/* one position GitLab refuses does not keep the other findings from being posted */
+		match posted {
+			Ok(()) => summary.posted += 1,
+			Err(err) => {
+				eprintln!("⚠️  Failed to comment on {}:{}: {}", path, finding.line, err);
+				summary.failed += 1;
+			},
+		}
+	}
+
+	Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn diff(path: &str, diff: &str) -> Diff {
+		Diff {
+			old_path: path.to_owned(),
+			new_path: path.to_owned(),
+			a_mode: "100644".to_owned(),
+			b_mode: "100644".to_owned(),
+			diff: diff.to_owned(),
+			new_file: false,
+			renamed_file: false,
+			deleted_file: false,
+		}
+	}
+
+	#[test]
+	fn test_changed_lines() {
+		let diffs = vec![diff(
+			"src/lib.rs",
+			"@@ -1,4 +1,5 @@\n use a;\n-use b;\n+use c;\n+use d;\n \n fn f() {}\n@@ -20,2 +21,3 @@ fn g() {\n x();\n+y();\n z();\n",
+		)];
+		let changed = changed_lines(&diffs);
+		let mut lines: Vec<(&u64, &String)> = changed["src/lib.rs"].lines.iter().collect();
+		lines.sort();
+		assert_eq!(lines, vec![(&2, &"use c;".to_owned()), (&3, &"use d;".to_owned()), (&22, &"y();".to_owned())]);
+	}
+
+	#[test]
+	fn test_changed_lines_of_renamed_file() {
+		let mut renamed = diff("src/new.rs", "@@ -1 +1 @@\n-a\n+b\n");
+		renamed.old_path = "src/old.rs".to_owned();
+		renamed.renamed_file = true;
+		let changed = changed_lines(&[renamed]);
+		assert_eq!(changed["src/new.rs"].old_path, "src/old.rs");
+	}
+
+	#[test]
+	fn test_resolve_path() {
+		let changed = changed_lines(&[diff("src/lib.rs", "@@ -0,0 +1 @@\n+a\n"), diff("lib.rs", "@@ -0,0 +1 @@\n+a\n")]);
+		assert_eq!(resolve_path("src/lib.rs", &changed).unwrap(), "src/lib.rs");
+		assert_eq!(resolve_path("file:///build/repo/src/lib.rs", &changed).unwrap(), "src/lib.rs");
+		assert_eq!(resolve_path("/build/repo/lib.rs", &changed).unwrap(), "lib.rs");
+		assert!(resolve_path("src/other.rs", &changed).is_none());
+	}
+
+	#[test]
+	fn test_marker_round_trip() {
+		let finding = Finding {
+			path: "src/lib.rs".to_owned(),
+			line: 3,
+			severity: "error".to_owned(),
+			rule: Some("E1".to_owned()),
+			message: "boom".to_owned(),
+		};
+		let fingerprint = fingerprint("src/lib.rs", &finding, "let x = 1;");
+		let discussion = |body: String| -> Discussion {
+			serde_json::from_value(serde_json::json!({
+				"id": "abc",
+				"notes": [{
+					"id": 1,
+					"body": body,
+					"author": { "id": 1, "name": "CI", "username": "ci-bot" },
+					"created_at": "2025-01-01T00:00:00Z",
+					"resolved": false,
+				}]
+			}))
+			.unwrap()
+		};
+		let posted = discussion(discussion_body(&finding, &fingerprint, "main::lint clippy"));
+		assert_eq!(marker_of(&posted), Some((fingerprint.as_str(), "main::lint clippy")));
+	}
+
+	#[test]
+	fn test_fingerprint_ignores_line_number() {
+		let finding = |line: u64| Finding {
+			path: "src/lib.rs".to_owned(),
+			line,
+			severity: "warning".to_owned(),
+			rule: Some("unused".to_owned()),
+			message: "unused variable".to_owned(),
+		};
+		/* moved by an edit above it, and reindented */
+		let fingerprint_at = |line: u64, code: &str| fingerprint("src/lib.rs", &finding(line), code);
+		assert_eq!(fingerprint_at(3, "let x = 1;"), fingerprint_at(9, "  let x =  1;"));
+		assert_ne!(fingerprint_at(3, "let x = 1;"), fingerprint_at(3, "let y = 1;"));
+	}
+}
@@ -0,0 +1,237 @@
+//! Templates for the strings `out` sends to GitLab (status names, descriptions, links, notes).
+//!
+//! Syntax:
+//!
+//! * `%NAME%` is replaced by the value of `NAME`. Unknown variables are an error.
+//! * `%NAME:-default%` falls back to `default` when `NAME` is unknown or empty.
+//! * `%FILE:path%` is replaced by the (trimmed) contents of `path`, relative to the build directory.
+//! * `%if NAME%...%else%...%endif%` keeps the first branch when `NAME` is set and not empty (`%if !NAME%` negates).
+//! * `%%` is a literal `%`. A `%` which does not start one of the above is kept as is, so `85% done` needs no escaping.
+use crate::common::MergeRequest;
+use anyhow::{
+	anyhow,
+	Context,
+	Result,
+};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+	Text(&'a str),
+	Var { name: &'a str, default: Option<&'a str> },
+	File(&'a str),
+	If { name: &'a str, negate: bool },
+	Else,
+	EndIf,
+}
+
+fn is_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Interpret the text between two `%`, or `None` if it is not a placeholder.
+fn parse_placeholder(inner: &str) -> Option<Token<'_>> {
+	match inner {
+		"else" => return Some(Token::Else),
+		"endif" => return Some(Token::EndIf),
+		_ => {},
+	}
+	if let Some(condition) = inner.strip_prefix("if ") {
+		let condition = condition.trim();
+		let (name, negate) = match condition.strip_prefix('!') {
+			Some(name) => (name.trim(), true),
+			None => (condition, false),
+		};
+		return is_name(name).then_some(Token::If { name, negate });
+	}
+	if let Some(path) = inner.strip_prefix("FILE:") {
+		return (!path.is_empty()).then_some(Token::File(path));
+	}
+	let (name, default) = match inner.split_once(":-") {
+		Some((name, default)) => (name, Some(default)),
+		None => (inner, None),
+	};
+	is_name(name).then_some(Token::Var { name, default })
+}
+
+fn tokenize(template: &str) -> Vec<Token<'_>> {
+	let mut tokens = Vec::new();
+	let mut rest = template;
+	let mut text_start = 0;
+	let mut offset = 0;
+
+	while let Some(start) = rest[offset..].find('%').map(|index| index + offset) {
+		if rest[start + 1..].starts_with('%') {
+			tokens.push(Token::Text(&rest[text_start..start + 1]));
+			rest = &rest[start + 2..];
+			text_start = 0;
+			offset = 0;
+			continue;
+		}
+		let placeholder = rest[start + 1..]
+			.find('%')
+			.and_then(|end| parse_placeholder(&rest[start + 1..start + 1 + end]).map(|token| (token, end)));
+		match placeholder {
+			Some((token, end)) => {
+				if text_start < start {
+					tokens.push(Token::Text(&rest[text_start..start]));
+				}
+				tokens.push(token);
+				rest = &rest[start + end + 2..];
+				text_start = 0;
+				offset = 0;
+			},
+			None => offset = start + 1,
+		}
+	}
+	if !rest[text_start..].is_empty() {
+		tokens.push(Token::Text(&rest[text_start..]));
+	}
+	tokens
+}
+
+/// Variables and files available to templates.
+#[derive(Debug, Default)]
+pub struct TemplateContext {
+	vars: HashMap<String, String>,
+	directory: Option<PathBuf>,
+}
+
+impl TemplateContext {
+	/// Context with the Concourse build metadata (`BUILD_*` and `ATC_EXTERNAL_URL`) and files of `directory`.
+	pub fn from_build_env(directory: impl Into<PathBuf>) -> Self {
+		let vars = env::vars().filter(|(name, _)| name.starts_with("BUILD_") || name == "ATC_EXTERNAL_URL").collect();
+		Self {
+			vars,
+			directory: Some(directory.into()),
+		}
+	}
+
+	pub fn set(&mut self, name: &str, value: impl Into<String>) -> &mut Self {
+		self.vars.insert(name.to_owned(), value.into());
+		self
+	}
+
+	/// Add the `MR_*` variables describing a merge request.
+	pub fn set_merge_request(&mut self, mr: &MergeRequest) -> &mut Self {
+		self.set("MR_IID", mr.iid.to_string())
+			.set("MR_TITLE", mr.title.as_str())
+			.set("MR_AUTHOR", mr.author.name.as_str())
+			.set("MR_AUTHOR_USERNAME", mr.author.username.as_str())
+			.set("MR_STATE", mr.state.as_str())
+			.set("MR_SOURCE_BRANCH", mr.source_branch.as_deref().unwrap_or_default())
+			.set("MR_TARGET_BRANCH", mr.target_branch.as_str())
+			.set("MR_LABELS", mr.labels.join(","))
+			.set("MR_SHA", mr.sha.as_deref().unwrap_or_default())
+			.set("MR_URL", mr.web_url.as_str())
+	}
+
+	fn read_file(&self, path: &str) -> Result<String> {
+		let path = match &self.directory {
+			Some(directory) => directory.join(path),
+			None => PathBuf::from(path),
+		};
+		let contents =
+			std::fs::read_to_string(&path).with_context(|| anyhow!("failed to read `{}` in template", path.display()))?;
+		Ok(contents.trim().to_owned())
+	}
+
+	fn is_set(&self, name: &str) -> bool {
+		self.vars.get(name).is_some_and(|value| !value.is_empty())
+	}
+
+	pub fn render(&self, template: &str) -> Result<String> {
+		let mut output = String::new();
+		/* one entry per open `%if%`: whether its current branch is rendered */
+		let mut branches = Vec::<bool>::new();
+		let mut conditions = Vec::<bool>::new();
+
+		for token in tokenize(template) {
+			let active = branches.last().copied().unwrap_or(true);
+			match token {
+				Token::If { name, negate } => {
+					let condition = self.is_set(name) != negate;
+					branches.push(active && condition);
+					conditions.push(condition);
+				},
+				Token::Else => {
+					let condition = conditions.last().ok_or_else(|| anyhow!("`%else%` without `%if%` in `{}`", template))?;
+					let parent = branches.len() < 2 || branches[branches.len() - 2];
+					*branches.last_mut().unwrap() = parent && !condition;
+				},
+				Token::EndIf => {
+					branches.pop().ok_or_else(|| anyhow!("`%endif%` without `%if%` in `{}`", template))?;
+					conditions.pop();
+				},
+				_ if !active => {},
+				Token::Text(text) => output.push_str(text),
+				Token::Var { name, default } => match (self.vars.get(name), default) {
+					(Some(value), Some(default)) if value.is_empty() => output.push_str(default),
+					(Some(value), _) => output.push_str(value),
+					(None, Some(default)) => output.push_str(default),
+					(None, None) => return Err(anyhow!("unknown variable `{}` in `{}`", name, template)),
+				},
+				Token::File(path) => output.push_str(&self.read_file(path)?),
+			}
+		}
+		if !branches.is_empty() {
+			return Err(anyhow!("`%if%` without `%endif%` in `{}`", template));
+		}
+		Ok(output)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rstest::rstest;
+
+	fn context() -> TemplateContext {
+		let mut context = TemplateContext::default();
+		context.set("BUILD_TEAM_NAME", "main").set("BUILD_JOB_NAME", "unit").set("EMPTY", "");
+		context
+	}
+
+	#[rstest]
+	#[case::plain("no placeholder", "no placeholder")]
+	#[case::legacy("%BUILD_TEAM_NAME%::%BUILD_JOB_NAME%", "main::unit")]
+	#[case::literal_percent("85% done, 100%", "85% done, 100%")]
+	#[case::escaped_percent("100%%", "100%")]
+	#[case::default_unknown("%MISSING:-none%", "none")]
+	#[case::default_empty("%EMPTY:-none%", "none")]
+	#[case::default_set("%BUILD_JOB_NAME:-none%", "unit")]
+	#[case::if_set("%if BUILD_JOB_NAME%job %BUILD_JOB_NAME%%endif%", "job unit")]
+	#[case::if_unset("a%if EMPTY%b%else%c%endif%d", "acd")]
+	#[case::if_negated("%if !MISSING%yes%endif%", "yes")]
+	#[case::nested("%if BUILD_JOB_NAME%%if EMPTY%x%else%y%endif%%else%z%endif%", "y")]
+	#[case::unknown_in_inactive_branch("%if EMPTY%%MISSING%%endif%ok", "ok")]
+	fn test_render(#[case] template: &str, #[case] expect: &str) {
+		assert_eq!(context().render(template).unwrap(), expect);
+	}
+
+	#[rstest]
+	#[case::unknown("%MISSING%")]
+	#[case::unclosed_if("%if BUILD_JOB_NAME%x")]
+	#[case::stray_endif("x%endif%")]
+	#[case::stray_else("x%else%")]
+	fn test_render_errors(#[case] template: &str) {
+		assert!(context().render(template).is_err());
+	}
+
+	#[test]
+	fn test_render_file() {
+		let directory = env::temp_dir().join(format!("gitlab-mr-template-{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		std::fs::write(directory.join("summary.txt"), "3 passed\n").unwrap();
+		let context = TemplateContext {
+			directory: Some(directory),
+			..Default::default()
+		};
+		assert_eq!(context.render("tests: %FILE:summary.txt%").unwrap(), "tests: 3 passed");
+		assert!(context.render("%FILE:missing.txt%").is_err());
+	}
+}
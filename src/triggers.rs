@@ -0,0 +1,149 @@
+//! Rebuilds of an already built merge request, requested with a comment such as `/retest` or a label.
+use crate::common::{
+	LabelEvent,
+	MergeRequest,
+	Note,
+};
+use crate::filters::project_access_level;
+use anyhow::Result;
+use chrono::{
+	DateTime,
+	Utc,
+};
+use gitlab::api::{
+	self,
+	common::SortOrder,
+	paged,
+	projects::merge_requests::{
+		notes::{
+			MergeRequestNotes,
+			NoteOrderBy,
+		},
+		EditMergeRequest,
+		MergeRequestResourceLabelEvents,
+	},
+	Pagination,
+	Query,
+};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Whether a line of `body` is one of `commands`, possibly followed by arguments.
+pub fn is_command(body: &str, commands: &[String]) -> bool {
+	body.lines().map(str::trim).any(|line| {
+		commands.iter().any(|command| {
+			line.strip_prefix(command.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+		})
+	})
+}
+
+/// Finds rebuild commands among the notes of merge requests.
+pub struct RebuildCommands<'a> {
+	commands: &'a [String],
+	min_access_level: u64,
+	/* access level of each note author seen so far, per project */
+	access_levels: HashMap<(String, u64), u64>,
+}
+
+impl<'a> RebuildCommands<'a> {
+	pub fn new(commands: &'a [String], min_access_level: u64) -> Self {
+		Self {
+			commands,
+			min_access_level,
+			access_levels: HashMap::new(),
+		}
+	}
+
+	/// The rebuild commands posted on `mr` after `since` which are not `consumed` yet, oldest first.
+	///
+	/// Commands from users below the minimum access level on `project` are ignored.
+	pub fn find<C: api::Client>(
+		&mut self,
+		client: &C,
+		project: &str,
+		mr: &MergeRequest,
+		since: DateTime<Utc>,
+		consumed: &HashMap<u64, String>,
+	) -> Result<Vec<Note>> {
+		/* posting a note updates the merge request */
+		if DateTime::<Utc>::from_str(&mr.updated_at)? < since {
+			return Ok(Vec::new());
+		}
+		let notes: Vec<Note> = paged(
+			MergeRequestNotes::builder()
+				.project(project)
+				.merge_request(mr.iid)
+				.order_by(NoteOrderBy::CreatedAt)
+				.sort(SortOrder::Descending)
+				.build()?,
+			Pagination::Limit(100),
+		)
+		.query(client)?;
+
+		let mut commands = Vec::new();
+		for note in notes {
+			if DateTime::<Utc>::from_str(&note.created_at)? < since {
+				break;
+			}
+			if note.system || consumed.contains_key(&note.id) || !is_command(&note.body, self.commands) {
+				continue;
+			}
+			let key = (project.to_owned(), note.author.id);
+			let access_level = match self.access_levels.get(&key) {
+				Some(&access_level) => access_level,
+				None => {
+					let access_level = project_access_level(client, project, note.author.id)?;
+					self.access_levels.insert(key, access_level);
+					access_level
+				},
+			};
+			if access_level < self.min_access_level {
+				eprintln!("  Ignoring rebuild command of `{}` in note {}: access level too low", note.author.username, note.id);
+				continue;
+			}
+			commands.push(note);
+		}
+		commands.reverse();
+		Ok(commands)
+	}
+}
+
+/// The event which added `label` to `mr`, if `mr` has the label.
+///
+/// Its ID identifies the rebuild request: the same label added again is a new request.
+pub fn label_event<C: api::Client>(client: &C, project: &str, mr: &MergeRequest, label: &str) -> Result<Option<LabelEvent>> {
+	if !mr.labels.iter().any(|name| name == label) {
+		return Ok(None);
+	}
+	let events: Vec<LabelEvent> =
+		paged(MergeRequestResourceLabelEvents::builder().project(project).merge_request(mr.iid).build()?, Pagination::All)
+			.query(client)?;
+	Ok(events
+		.into_iter()
+		.filter(|event| event.action == "add" && event.label.as_ref().is_some_and(|event_label| event_label.name == label))
+		.max_by_key(|event| event.id))
+}
+
+pub fn remove_label<C: api::Client>(client: &C, project: &str, mr: &MergeRequest, label: &str) -> Result<()> {
+	api::ignore(EditMergeRequest::builder().project(project).merge_request(mr.iid).remove_label(label).build()?)
+		.query(client)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::is_command;
+	use rstest::rstest;
+
+	#[rstest]
+	#[case::exact("/retest", true)]
+	#[case::arguments("/retest unit", true)]
+	#[case::surrounding_lines("flaky again\n  /rebuild  \nthanks", true)]
+	#[case::prefix_of_word("/retesting", false)]
+	#[case::inline("please /retest", false)]
+	#[case::quoted("> /retest", false)]
+	fn test_is_command(#[case] body: &str, #[case] expect: bool) {
+		let commands = vec!["/retest".to_owned(), "/rebuild".to_owned()];
+		assert_eq!(is_command(body, &commands), expect);
+	}
+}